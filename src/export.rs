@@ -0,0 +1,110 @@
+//! Writes range query (matrix) results to Parquet or Arrow IPC files in long
+//! format: one row per sample with `timestamp`, `value` and one column per label.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, TimestampMillisecondArray};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use clap::ValueEnum;
+use parquet::arrow::ArrowWriter;
+use serde_json::Value;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    Parquet,
+    /// Arrow IPC file format
+    Arrow,
+}
+
+/// Summary of a finished export.
+pub struct ExportStats {
+    pub rows: usize,
+    pub series: usize,
+}
+
+pub fn write_matrix(data: &Value, format: ExportFormat, path: &str) -> Result<ExportStats> {
+    if data.get("resultType").and_then(Value::as_str) != Some("matrix") {
+        bail!("export expects a matrix result");
+    }
+    let series = data
+        .get("result")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+
+    let labels: BTreeSet<&str> = series
+        .iter()
+        .filter_map(|item| item.get("metric").and_then(Value::as_object))
+        .flat_map(|metric| metric.keys().map(String::as_str))
+        .collect();
+
+    let mut timestamps = Vec::new();
+    let mut values = Vec::new();
+    let mut label_columns: Vec<Vec<Option<&str>>> = vec![Vec::new(); labels.len()];
+    for item in series {
+        let metric = item.get("metric");
+        let samples = item.get("values").and_then(Value::as_array);
+        for sample in samples.into_iter().flatten() {
+            let Some(timestamp) = sample.get(0).and_then(Value::as_f64) else {
+                continue;
+            };
+            let value = sample
+                .get(1)
+                .and_then(Value::as_str)
+                .and_then(|raw| raw.parse::<f64>().ok())
+                .unwrap_or(f64::NAN);
+            timestamps.push((timestamp * 1000.0).round() as i64);
+            values.push(value);
+            for (column, name) in label_columns.iter_mut().zip(&labels) {
+                column.push(metric.and_then(|m| m.get(*name)).and_then(Value::as_str));
+            }
+        }
+    }
+
+    let mut fields = vec![
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("value", DataType::Float64, false),
+    ];
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(TimestampMillisecondArray::from(timestamps).with_timezone("UTC")),
+        Arc::new(Float64Array::from(values)),
+    ];
+    for (name, column) in labels.iter().zip(label_columns) {
+        // Keep label columns from shadowing the sample columns.
+        let name = match *name {
+            "timestamp" | "value" => format!("label_{name}"),
+            name => name.to_string(),
+        };
+        fields.push(Field::new(name, DataType::Utf8, true));
+        columns.push(Arc::new(StringArray::from(column)));
+    }
+
+    let schema = Arc::new(Schema::new(fields));
+    let batch = RecordBatch::try_new(schema.clone(), columns).context("failed to build batch")?;
+    let file = File::create(path).with_context(|| format!("failed to create {path}"))?;
+    match format {
+        ExportFormat::Parquet => {
+            let mut writer = ArrowWriter::try_new(file, schema, None)?;
+            writer.write(&batch)?;
+            writer.close()?;
+        }
+        ExportFormat::Arrow => {
+            let mut writer = FileWriter::try_new(file, &schema)?;
+            writer.write(&batch)?;
+            writer.finish()?;
+        }
+    }
+
+    Ok(ExportStats {
+        rows: batch.num_rows(),
+        series: series.len(),
+    })
+}
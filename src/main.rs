@@ -1,10 +1,38 @@
+mod export;
+mod plot;
+mod rotate;
+mod stream;
+
 use anyhow::{Context, Result, bail};
-use clap::{Parser, Subcommand};
-use reqwest::blocking::{Client, RequestBuilder};
-use serde::Deserialize;
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use regex::Regex;
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{
+    AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE, COOKIE, HOST, HeaderName, HeaderValue,
+    PROXY_AUTHORIZATION,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::io::{self, BufWriter, IsTerminal, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::process::{Command, ExitCode, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use twox_hash::XxHash3_128;
 use url::Url;
 
+use export::ExportFormat;
+use rotate::{Rotate, RotatingFile};
+
 #[derive(Parser)]
 #[command(
     name = "prometheus-metrics",
@@ -12,13 +40,45 @@ use url::Url;
     about = "Query Prometheus/VictoriaMetrics endpoints"
 )]
 struct Cli {
-    #[arg(long, env = "PROMQL_BASE_URL", value_name = "URL")]
-    base_url: String,
+    #[arg(
+        long,
+        env = "PROMQL_BASE_URL",
+        value_name = "URL",
+        required_unless_present_any = ["k8s_service", "secrets_dir"]
+    )]
+    base_url: Option<String>,
+
+    /// Directory with base_url, token, username and password files (e.g. a mounted
+    /// secret) used for any of those settings not given otherwise
+    #[arg(long, env = "PROMQL_SECRETS_DIR", value_name = "PATH")]
+    secrets_dir: Option<String>,
+
+    /// Ignore PROMQL_* environment variables; every setting must be passed as a flag
+    #[arg(long, default_value_t = false)]
+    no_env: bool,
+
+    /// In-cluster Kubernetes service as [scheme://]service.namespace[:port][/path];
+    /// takes precedence over --base-url
+    #[arg(long, value_name = "SERVICE")]
+    k8s_service: Option<String>,
+
+    /// Use the base URL as given instead of appending a trailing slash; API paths
+    /// are still joined below its path with a single `/`
+    #[arg(long, default_value_t = false)]
+    no_normalize_base: bool,
+
+    /// VictoriaMetrics cluster tenant; appends /select/<tenant>/prometheus to the base URL
+    #[arg(long, value_name = "ACCOUNT[:PROJECT]", value_parser = parse_vm_tenant)]
+    vm_tenant: Option<String>,
 
     /// Basic auth in the form user:password
     #[arg(long, env = "PROMQL_AUTH")]
     auth: Option<String>,
 
+    /// File containing basic auth as a single user:password line (after --auth)
+    #[arg(long, env = "PROMQL_AUTH_FILE", value_name = "PATH")]
+    auth_file: Option<String>,
+
     #[arg(long, env = "PROMQL_USER")]
     user: Option<String>,
 
@@ -29,10 +89,159 @@ struct Cli {
     #[arg(long, env = "PROMQL_BEARER")]
     bearer: Option<String>,
 
+    /// Shell command that prints a bearer token on stdout (run once, after --bearer)
+    #[arg(long, env = "PROMQL_AUTH_COMMAND", value_name = "COMMAND")]
+    auth_command: Option<String>,
+
+    /// Before running the command, poll /-/ready (or buildinfo) for up to this long
+    #[arg(long, value_name = "DUR", value_parser = parse_duration)]
+    wait_for_server: Option<Duration>,
+
+    /// Kill --auth-command if it runs longer than this
+    #[arg(long, default_value = "10s", value_parser = parse_duration, value_name = "DUR")]
+    auth_command_timeout: Duration,
+
+    /// Color the final error message on stderr; auto colors a terminal unless NO_COLOR is set
+    #[arg(long, value_enum, default_value_t = ColorMode::Auto, value_name = "WHEN")]
+    error_color: ColorMode,
+
+    /// Log requests and responses to stderr
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+
+    /// Append every request and response (with body) to this file as NDJSON, independent of -v
+    #[arg(long, value_name = "PATH")]
+    transcript: Option<String>,
+
+    /// Format of verbose log lines
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Client-side HTTP request timeout (e.g. 30s); reqwest's 30s default when unset
+    #[arg(long, value_name = "DUR", value_parser = parse_duration)]
+    http_timeout: Option<Duration>,
+
+    /// Speak HTTP/2 without negotiation (for h2-only gateways, including plain http://)
+    #[arg(long, default_value_t = false, conflicts_with = "http1_only")]
+    http2_prior_knowledge: bool,
+
+    /// Only use HTTP/1.1 (for proxies that mishandle HTTP/2)
+    #[arg(long, default_value_t = false)]
+    http1_only: bool,
+
+    /// Hostname to present via TLS SNI (and certificate checks) while connecting
+    /// to the base URL's address, e.g. when addressing a server by IP
+    #[arg(long, value_name = "HOST")]
+    tls_sni: Option<String>,
+
+    /// Refuse TLS connections below this protocol version (default: 1.2)
+    #[arg(long, value_enum, value_name = "VERSION")]
+    tls_min_version: Option<TlsVersion>,
+
+    /// Override the Host header sent with each request
+    #[arg(long, value_name = "HOST")]
+    host_header: Option<String>,
+
+    /// Extra HTTP header sent with every request (repeatable)
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+
+    /// File of `Name: Value` header lines (blank and # comment lines are ignored);
+    /// a --header with the same name wins
+    #[arg(long, value_name = "PATH")]
+    headers_file: Option<String>,
+
+    /// Timeout for establishing the TCP/TLS connection (e.g. 5s)
+    #[arg(long, value_name = "DUR", value_parser = parse_duration)]
+    connect_timeout: Option<Duration>,
+
+    /// Overall deadline: sets both the query --timeout and --http-timeout unless
+    /// those are given explicitly
+    #[arg(long, value_name = "DUR", value_parser = parse_duration)]
+    deadline: Option<Duration>,
+
+    /// On SIGINT/SIGTERM, abandon the in-flight request, print "cancelled" and exit
+    /// with 128 + the signal number instead of being killed abruptly
+    #[arg(long, default_value_t = false)]
+    deadline_from_context: bool,
+
+    /// Retry query/range requests up to N times on connection errors and HTTP 5xx
+    #[arg(long, default_value_t = 0, value_name = "N")]
+    retries: u32,
+
+    /// Delay before the first retry, doubled for each further attempt
+    #[arg(long, default_value = "1s", value_name = "DUR", value_parser = parse_duration)]
+    retry_backoff: Duration,
+
+    /// Retry query/range requests up to N times when the client-side --http-timeout expires
+    #[arg(long, default_value_t = 0, value_name = "N")]
+    timeout_retries: u32,
+
+    /// Delay before the first timeout retry, doubled for each further attempt
+    #[arg(long, default_value = "5s", value_name = "DUR", value_parser = parse_duration)]
+    timeout_retry_backoff: Duration,
+
+    /// Also retry (within --retries) when a query returns no data
+    #[arg(long, default_value_t = false)]
+    retry_on_empty: bool,
+
+    /// Gzip-compress POST request bodies (the server must accept Content-Encoding: gzip)
+    #[arg(long, default_value_t = false)]
+    compress_request: bool,
+
+    /// How to encode query parameters in POST bodies and GET URLs
+    #[arg(long, value_enum, default_value_t = Encoding::Form)]
+    encoding: Encoding,
+
+    /// Unit of numeric --time/--start/--end values and of sample timestamps in JSON output
+    #[arg(long, value_enum, default_value_t = EpochUnit::S)]
+    time_unit: EpochUnit,
+
+    /// Treat the query argument as the path of a file holding the query
+    #[arg(long, default_value_t = false)]
+    query_is_file: bool,
+
+    /// Rewrite `@ now-1h` style modifiers (and `@ <RFC3339>`) in the query to Unix timestamps
+    #[arg(long, default_value_t = false)]
+    expand_at: bool,
+
+    /// Extra request parameter sent with every API call (repeatable)
+    #[arg(long = "query-param", value_name = "KEY=VALUE", value_parser = parse_query_param)]
+    query_params: Vec<(String, String)>,
+
+    /// With --method auto, switch from GET to POST once the encoded parameters exceed this size
+    #[arg(long, default_value_t = 2048, value_name = "BYTES")]
+    post_threshold: usize,
+
+    /// Output format for query results
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Json)]
+    output: OutputFormat,
+
+    /// With -o ndjson, print range query series as they are parsed instead of buffering the response
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = [
+            "max_series",
+            "head",
+            "sort_by_value",
+            "assert_series",
+            "assert_series_min",
+            "assert_series_max",
+            "expect_gt",
+            "expect_lt",
+        ]
+    )]
+    stream: bool,
+
     /// Pretty-print JSON output
     #[arg(long, default_value_t = false)]
     pretty: bool,
 
+    /// Pretty-print JSON but keep arrays of plain values (e.g. samples) on one line
+    #[arg(long, default_value_t = false)]
+    json_compact_arrays: bool,
+
     /// Print only .data.result when available
     #[arg(long, default_value_t = false)]
     result: bool,
@@ -41,6 +250,175 @@ struct Cli {
     #[arg(long, default_value_t = false)]
     lines: bool,
 
+    /// Print list endpoints as a regex alternation, e.g. (web1|web2), for =~ matchers
+    #[arg(long, default_value_t = false, conflicts_with = "lines")]
+    as_regex: bool,
+
+    /// Print only sample values of vector/scalar results, one per line
+    #[arg(long, default_value_t = false)]
+    values_only: bool,
+
+    /// Print count, min, max, mean and p50/p90/p99 of a vector result's values
+    #[arg(long, default_value_t = false)]
+    summary: bool,
+
+    /// Format values with SI/byte/time suffixes in text output (--values-only, tail)
+    #[arg(long, default_value_t = false)]
+    humanize: bool,
+
+    /// Unit hint for --humanize
+    #[arg(long, value_enum, default_value_t = Unit::Count)]
+    unit: Unit,
+
+    /// Fail when a vector/matrix result has more than N series
+    #[arg(long, value_name = "N")]
+    max_series: Option<u64>,
+
+    /// Warn on stderr (but print everything) when a vector/matrix result has more than N series
+    #[arg(long, value_name = "N")]
+    series_limit_warn: Option<u64>,
+
+    /// With --max-series, print the first N series instead of failing
+    #[arg(long, default_value_t = false, requires = "max_series")]
+    truncate: bool,
+
+    /// Sort vector results by sample value (NaN last in either order)
+    #[arg(long, value_enum, value_name = "ORDER")]
+    sort_by_value: Option<SortOrder>,
+
+    /// Print only the first N series of a vector/matrix result (after --sort-by-value)
+    #[arg(long, value_name = "N")]
+    head: Option<usize>,
+
+    /// Print this token instead of an empty vector/matrix or null/NaN scalar result
+    #[arg(long, value_name = "STRING")]
+    null_as: Option<String>,
+
+    /// Round values to N decimal places in text output (JSON keeps full precision)
+    #[arg(long, value_name = "N")]
+    float_precision: Option<usize>,
+
+    /// Reduce each series of a matrix result to N points (largest-triangle-three-buckets)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(3..))]
+    downsample: Option<u64>,
+
+    /// Render the result with a Jinja-style template file (e.g. for Markdown reports)
+    #[arg(long, value_name = "PATH")]
+    template_file: Option<String>,
+
+    /// With -o openmetrics, look up # TYPE/# HELP lines via /api/v1/metadata
+    #[arg(long, default_value_t = false)]
+    fetch_metadata: bool,
+
+    /// Print a LABELS/VALUE table with each label set joined into one string by SEP
+    #[arg(long, value_name = "SEP")]
+    label_join: Option<String>,
+
+    /// Print vector results in sections per metric name (works with --values-only)
+    #[arg(long, default_value_t = false)]
+    group_by_name: bool,
+
+    /// Print each vector series with the timestamp of its sample instead of the value
+    #[arg(long, default_value_t = false)]
+    latest_timestamp: bool,
+
+    /// Sort --latest-timestamp output oldest first
+    #[arg(long, default_value_t = false, requires = "latest_timestamp")]
+    oldest_first: bool,
+
+    /// Print a matrix result as a table with one row per timestamp and one column per value of LABEL
+    #[arg(long, value_name = "LABEL")]
+    pivot_by: Option<String>,
+
+    /// How --pivot-by renders timestamps a series has no sample for
+    #[arg(long, value_enum, default_value_t = Fill::Empty, requires = "pivot_by")]
+    fill: Fill,
+
+    /// Flatten each result element into an object with dotted keys (metric.job, value.1)
+    #[arg(long, default_value_t = false)]
+    flatten: bool,
+
+    /// Split [timestamp, value] pairs into top-level `timestamp` and `value` fields, one object per sample
+    #[arg(long, default_value_t = false)]
+    sample_timestamp_as_column: bool,
+
+    /// Keep only labels whose names match this regex in result label sets
+    #[arg(long, value_name = "PATTERN")]
+    out_labels_regex: Option<Regex>,
+
+    /// Rename label OLD to NEW in result label sets (repeatable)
+    #[arg(long = "rename-label", value_name = "OLD=NEW", value_parser = parse_rename_label)]
+    rename_labels: Vec<(String, String)>,
+
+    /// Replace the values of this label with REDACTED in the output (repeatable)
+    #[arg(long, value_name = "LABEL")]
+    redact: Vec<String>,
+
+    /// Replace any label value matching this regex with REDACTED in the output
+    #[arg(long, value_name = "REGEX")]
+    redact_regex: Option<Regex>,
+
+    /// Report per-series staleness of vector results and fail if any sample is older
+    #[arg(long, value_name = "DUR", value_parser = parse_duration)]
+    max_staleness: Option<Duration>,
+
+    /// Exit non-zero unless the result has exactly N series
+    #[arg(long, value_name = "N")]
+    assert_series: Option<u64>,
+
+    /// Exit non-zero if the result has fewer than N series
+    #[arg(long, value_name = "N")]
+    assert_series_min: Option<u64>,
+
+    /// Exit non-zero if the result has more than N series
+    #[arg(long, value_name = "N")]
+    assert_series_max: Option<u64>,
+
+    /// Exit non-zero unless every sample value is greater than this
+    #[arg(long, value_name = "VALUE", allow_negative_numbers = true)]
+    expect_gt: Option<f64>,
+
+    /// Exit non-zero unless every sample value is less than this
+    #[arg(long, value_name = "VALUE", allow_negative_numbers = true)]
+    expect_lt: Option<f64>,
+
+    /// Fail unless the result has this `resultType`
+    #[arg(long, value_enum, value_name = "TYPE")]
+    expect_type: Option<ResultType>,
+
+    /// Which API response status values count as success
+    #[arg(long, value_enum, default_value_t = ExpectStatus::Success)]
+    expect_status: ExpectStatus,
+
+    /// Fail when a server warning contains this substring (repeatable)
+    #[arg(long, value_name = "SUBSTRING")]
+    abort_on_warning: Vec<String>,
+
+    /// Wrap JSON output with the query, time and base URL that produced it
+    #[arg(long, default_value_t = false)]
+    annotate: bool,
+
+    /// Print HTTP status, bytes received and round-trip time to stderr after the result
+    #[arg(long, default_value_t = false)]
+    meta: bool,
+
+    /// Print a timing breakdown (DNS, request, body read, JSON parse, output) to stderr at the end
+    #[arg(long, default_value_t = false)]
+    profile: bool,
+
+    /// Omit the trailing newline after the final line of output
+    #[arg(long, default_value_t = false)]
+    no_newline: bool,
+
+    /// Emit array output, or the `result` list of query output, in chunks of N
+    /// elements, flushing after each chunk
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    page_size: Option<u64>,
+
+    /// Set when the token or username/password were read from --secrets-dir
+    #[arg(skip)]
+    secrets_credentials: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -55,8 +433,23 @@ enum Commands {
         #[arg(long)]
         time: Option<String>,
         /// Optional query timeout (e.g. 30s)
-        #[arg(long)]
+        #[arg(long, env = "PROMQL_TIMEOUT")]
         timeout: Option<String>,
+        /// HTTP method; auto uses GET for short queries and POST above --post-threshold
+        #[arg(long, value_enum, default_value_t = Method::Auto)]
+        method: Method,
+        /// Override the staleness lookback window (e.g. 10m)
+        #[arg(long)]
+        lookback_delta: Option<String>,
+        /// Re-run the query until the assertion flags pass
+        #[arg(long, default_value_t = false)]
+        repeat_until: bool,
+        /// Time between attempts with --repeat-until
+        #[arg(long, default_value = "5s", value_parser = parse_duration, requires = "repeat_until")]
+        interval: Duration,
+        /// Give up --repeat-until after this long (waits forever when unset)
+        #[arg(long, value_parser = parse_duration, requires = "repeat_until")]
+        max_wait: Option<Duration>,
     },
 
     /// Range query
@@ -73,8 +466,147 @@ enum Commands {
         #[arg(long, default_value = "60s")]
         step: String,
         /// Optional query timeout (e.g. 30s)
+        #[arg(long, env = "PROMQL_TIMEOUT")]
+        timeout: Option<String>,
+        /// HTTP method; auto uses GET for short queries and POST above --post-threshold
+        #[arg(long, value_enum, default_value_t = Method::Auto)]
+        method: Method,
+        /// Override the staleness lookback window (e.g. 10m)
+        #[arg(long)]
+        lookback_delta: Option<String>,
+        /// Swap --start and --end (with a warning) when they are reversed
+        #[arg(long, default_value_t = false)]
+        auto_swap: bool,
+        /// Refuse queries that would return more points per series than this
+        #[arg(long, default_value_t = 11000)]
+        max_points: u64,
+        /// Run the query even if it exceeds --max-points
+        #[arg(long, default_value_t = false)]
+        force: bool,
+        /// Snap start down and end up to multiples of --step for stable buckets
+        #[arg(long, default_value_t = false)]
+        align_step: bool,
+        /// Snap start down and end up to UTC minute, hour or day boundaries
+        #[arg(long, value_enum, value_name = "UNIT")]
+        align_to: Option<AlignUnit>,
+        /// Use N times the server's scrape interval as the step instead of --step
+        #[arg(long, value_name = "N", conflicts_with = "step", value_parser = clap::value_parser!(u32).range(1..))]
+        step_scrapes: Option<u32>,
+        /// Split ranges over --max-points into chunks and stitch the results together
+        #[arg(long, default_value_t = false)]
+        auto_chunk: bool,
+        /// Number of chunks to fetch in parallel with --auto-chunk
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..), requires = "auto_chunk")]
+        chunk_concurrency: u64,
+    },
+
+    /// Run a range query and write the samples to a Parquet or Arrow file
+    Export {
+        /// PromQL query
+        query: String,
+        /// Range start (RFC3339 or Unix timestamp)
+        #[arg(long)]
+        start: String,
+        /// Range end (RFC3339 or Unix timestamp)
+        #[arg(long)]
+        end: String,
+        /// Step size (e.g. 60s)
+        #[arg(long, default_value = "60s")]
+        step: String,
+        /// Output file format
+        #[arg(long, value_enum, default_value_t = ExportFormat::Parquet)]
+        format: ExportFormat,
+        /// Output file path
+        #[arg(long, value_name = "PATH")]
+        out: String,
+    },
+
+    /// Run a range query and render it as a PNG line chart
+    Plot {
+        /// PromQL query
+        query: String,
+        /// Range start (RFC3339 or Unix timestamp)
+        #[arg(long)]
+        start: String,
+        /// Range end (RFC3339 or Unix timestamp)
+        #[arg(long)]
+        end: String,
+        /// Step size (e.g. 60s)
+        #[arg(long, default_value = "60s")]
+        step: String,
+        /// Output PNG path
+        #[arg(long, value_name = "PATH")]
+        out: String,
+        /// Image width in pixels
+        #[arg(long, default_value_t = 1024)]
+        width: u32,
+        /// Image height in pixels
+        #[arg(long, default_value_t = 480)]
+        height: u32,
+    },
+
+    /// Re-run an instant query periodically, printing series whose value changed
+    Tail {
+        /// PromQL query
+        query: String,
+        /// Time between queries (e.g. 15s)
+        #[arg(long, default_value = "15s", value_parser = parse_duration)]
+        interval: Duration,
+        /// Stop after this long (runs until interrupted when unset)
+        #[arg(long, value_parser = parse_duration)]
+        duration: Option<Duration>,
+        /// Append the changes to this file instead of stdout
+        #[arg(long, value_name = "PATH")]
+        output_file: Option<String>,
+        /// Continue in PATH.1, PATH.2, ... once the current file reaches a size
+        /// (e.g. 100MB) or age (e.g. 1h)
+        #[arg(long, value_name = "SIZE|INTERVAL", value_parser = parse_rotate, requires = "output_file")]
+        output_file_rotate: Option<Rotate>,
+    },
+
+    /// Compare each series of an instant query now against `--offset` ago
+    Compare {
+        /// PromQL query
+        query: String,
+        /// How far back the baseline is evaluated (e.g. 1w)
+        #[arg(long, default_value = "1w", value_parser = parse_duration)]
+        offset: Duration,
+        /// Average the query over this window at both points (e.g. 1h)
+        #[arg(long)]
+        window: Option<String>,
+    },
+
+    /// Print a hash of an instant query's result that only changes when its series or values do
+    Hash {
+        /// PromQL query
+        query: String,
+        /// Evaluation timestamp (RFC3339 or Unix timestamp)
+        #[arg(long)]
+        time: Option<String>,
+    },
+
+    /// Run instant queries from a file (one per line) and emit NDJSON
+    Batch {
+        /// File with one PromQL query per line (blank and # comment lines are ignored)
+        file: String,
+        /// Evaluation timestamp (RFC3339 or Unix timestamp)
         #[arg(long)]
+        time: Option<String>,
+        /// Optional query timeout (e.g. 30s)
+        #[arg(long, env = "PROMQL_TIMEOUT")]
         timeout: Option<String>,
+        /// Print only queries that failed or returned no data
+        #[arg(long, default_value_t = false)]
+        only_errors: bool,
+        /// Record failures as {"errorType", "error", "status"} objects instead of strings
+        #[arg(long, default_value_t = false)]
+        structured_errors: bool,
+    },
+
+    /// Validate the expressions of a Prometheus rules file with /api/v1/format_query
+    CheckRules {
+        /// Recording/alerting rules YAML file
+        file: String,
     },
 
     /// List label values
@@ -84,16 +616,87 @@ enum Commands {
         /// Matchers to filter label values (repeatable)
         #[arg(long = "match")]
         matches: Vec<String>,
+        #[command(flatten)]
+        matchers: MatcherArgs,
+    },
+
+    /// Rank label names by their number of distinct values
+    LabelsCardinality {
+        /// Matchers to scope the label names and values (repeatable)
+        #[arg(long = "match")]
+        matches: Vec<String>,
+        #[command(flatten)]
+        matchers: MatcherArgs,
+        /// Number of label value lookups to run in parallel
+        #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u64).range(1..))]
+        concurrency: u64,
+    },
+
+    /// Per-target up/down summary joining the `up` metric with /api/v1/targets
+    Health,
+
+    /// Check the /-/ready endpoint; exits non-zero unless the server is ready
+    Ready,
+
+    /// Check the /-/healthy endpoint; exits non-zero unless the server is healthy
+    Healthy,
+
+    /// Trigger a config reload via POST /-/reload (needs --web.enable-lifecycle)
+    Reload,
+
+    /// Check DNS, TCP connect, /-/ready, credentials and a trivial query, with hints
+    Probe,
+
+    /// Print the server's current Unix time by evaluating `time()`
+    ServerTime {
+        /// Print the time as RFC3339 instead of a Unix timestamp
+        #[arg(long, default_value_t = false)]
+        rfc3339: bool,
+    },
+
+    /// Print /api/v1/status/buildinfo, optionally asserting how recent the build is
+    BuildInfo {
+        /// Exit non-zero unless the server was built after this time (RFC3339 or
+        /// Unix timestamp), e.g. to verify a deploy rolled out
+        #[arg(long, value_name = "TIME")]
+        assert_build_after: Option<String>,
+    },
+
+    /// Print the effective settings after flags, environment and --secrets-dir are resolved
+    ConfigDump {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = DumpFormat::Json)]
+        format: DumpFormat,
     },
 
     /// List job label values
-    Jobs,
+    Jobs {
+        /// Print a table of jobs with their number of `up` series (instances), largest first
+        #[arg(long, default_value_t = false)]
+        with_counts: bool,
+    },
 
     /// List metric names
     Metrics {
         /// Case-insensitive substring filter
         #[arg(long)]
         filter: Option<String>,
+        /// Keep only metrics of this type according to /api/v1/metadata
+        #[arg(long, value_enum)]
+        metric_type: Option<MetricType>,
+    },
+
+    /// List metric names with their type and help text from /api/v1/metadata
+    Catalog {
+        /// Case-insensitive substring filter
+        #[arg(long)]
+        filter: Option<String>,
+        /// Keep only metric names matching this regex
+        #[arg(long, value_name = "PATTERN")]
+        regex: Option<Regex>,
+        /// Keep only metrics of this type
+        #[arg(long, value_enum)]
+        metric_type: Option<MetricType>,
     },
 
     /// Find series matching selector(s)
@@ -101,6 +704,23 @@ enum Commands {
         /// Matchers to filter series (repeatable)
         #[arg(long = "match")]
         matches: Vec<String>,
+        #[command(flatten)]
+        matchers: MatcherArgs,
+        /// Range start (RFC3339 or Unix timestamp)
+        #[arg(long)]
+        start: Option<String>,
+        /// Range end (RFC3339 or Unix timestamp)
+        #[arg(long)]
+        end: Option<String>,
+    },
+
+    /// Print the number of series matching selector(s)
+    SeriesCount {
+        /// Matchers to filter series (repeatable)
+        #[arg(long = "match")]
+        matches: Vec<String>,
+        #[command(flatten)]
+        matchers: MatcherArgs,
         /// Range start (RFC3339 or Unix timestamp)
         #[arg(long)]
         start: Option<String>,
@@ -110,246 +730,4428 @@ enum Commands {
     },
 }
 
-#[derive(Deserialize)]
-struct ApiResponse {
-    status: String,
-    data: Option<Value>,
-    #[serde(rename = "errorType")]
-    error_type: Option<String>,
-    error: Option<String>,
-    warnings: Option<Vec<String>>,
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// Prometheus API JSON
+    Json,
+    /// `{labels, samples: [{value, timestamp}]}` per series, as used by remote-write tooling
+    RemotewriteJson,
+    /// OpenMetrics text format
+    Openmetrics,
+    /// One JSON object per line for each result element (series)
+    Ndjson,
+    /// GitHub-flavored Markdown table with a row per sample
+    Markdown,
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let base = normalize_base(&cli.base_url)?;
-    let client = Client::builder()
-        .user_agent(format!("prometheus-metrics/{}", env!("CARGO_PKG_VERSION")))
-        .build()
-        .context("failed to build HTTP client")?;
+#[derive(Clone, Copy, ValueEnum)]
+enum Unit {
+    Bytes,
+    Seconds,
+    Count,
+}
 
-    match &cli.command {
-        Commands::Query {
-            query,
-            time,
-            timeout,
-        } => {
-            let url = base.join("api/v1/query").context("invalid base URL")?;
-            let mut params = vec![("query".to_string(), query.clone())];
-            if let Some(time) = time {
-                params.push(("time".to_string(), time.clone()));
-            }
-            if let Some(timeout) = timeout {
-                params.push(("timeout".to_string(), timeout.clone()));
-            }
-            let response = post_form(&cli, &client, url, params)?;
-            output_data(&cli, response)?;
-        }
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
 
-        Commands::Range {
-            query,
-            start,
-            end,
-            step,
-            timeout,
-        } => {
-            let url = base
-                .join("api/v1/query_range")
-                .context("invalid base URL")?;
-            let mut params = vec![
-                ("query".to_string(), query.clone()),
-                ("start".to_string(), start.clone()),
-                ("end".to_string(), end.clone()),
-                ("step".to_string(), step.clone()),
-            ];
-            if let Some(timeout) = timeout {
-                params.push(("timeout".to_string(), timeout.clone()));
-            }
-            let response = post_form(&cli, &client, url, params)?;
-            output_data(&cli, response)?;
-        }
+/// The parts of a Prometheus rules file that `check-rules` needs.
+#[derive(Deserialize)]
+struct RuleFile {
+    #[serde(default)]
+    groups: Vec<RuleGroup>,
+}
 
-        Commands::Labels { label, matches } => {
-            let url = base
-                .join(&format!("api/v1/label/{label}/values"))
-                .context("invalid base URL")?;
-            let params = build_match_params(matches.clone(), None, None);
-            let response = get_query(&cli, &client, url, params)?;
-            output_list(&cli, response)?;
-        }
+#[derive(Deserialize)]
+struct RuleGroup {
+    name: String,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
 
-        Commands::Jobs => {
-            let url = base
-                .join("api/v1/label/job/values")
-                .context("invalid base URL")?;
-            let response = get_query(&cli, &client, url, Vec::new())?;
-            output_list(&cli, response)?;
-        }
+#[derive(Deserialize)]
+struct Rule {
+    record: Option<String>,
+    alert: Option<String>,
+    expr: String,
+}
 
-        Commands::Metrics { filter } => {
-            let url = base
-                .join("api/v1/label/__name__/values")
-                .context("invalid base URL")?;
-            let mut response = get_query(&cli, &client, url, Vec::new())?;
-            if let Some(filter) = filter {
-                response = filter_values(response, filter)?;
-            }
-            output_list(&cli, response)?;
-        }
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum ExpectStatus {
+    /// Fail unless the response status is "success"
+    Success,
+    /// Accept any status on HTTP 2xx responses, warning when it is not "success"
+    Any,
+}
 
-        Commands::Series {
-            matches,
-            start,
-            end,
-        } => {
-            if matches.is_empty() {
-                bail!("--match is required for series queries");
-            }
-            let url = base.join("api/v1/series").context("invalid base URL")?;
-            let params = build_match_params(matches.clone(), start.clone(), end.clone());
-            let response = get_query(&cli, &client, url, params)?;
-            output_data(&cli, response)?;
-        }
-    }
+#[derive(Clone, Copy, ValueEnum)]
+enum ResultType {
+    Vector,
+    Matrix,
+    Scalar,
+    String,
+}
 
-    Ok(())
+#[derive(Clone, Copy, ValueEnum)]
+enum Encoding {
+    /// application/x-www-form-urlencoded (spaces become `+`)
+    Form,
+    /// RFC 3986 percent-encoding (spaces become `%20`)
+    Query,
 }
 
-fn normalize_base(base: &str) -> Result<Url> {
-    let mut base = base.to_string();
-    if !base.ends_with('/') {
-        base.push('/');
-    }
-    Url::parse(&base).context("invalid base URL")
+/// Metric types as named by the `type` field of /api/v1/metadata.
+#[derive(Clone, Copy, ValueEnum)]
+enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
 }
 
-fn apply_auth(request: RequestBuilder, cli: &Cli) -> Result<RequestBuilder> {
-    if let Some(token) = &cli.bearer {
-        return Ok(request.bearer_auth(token));
-    }
+#[derive(Clone, Copy, ValueEnum)]
+enum Fill {
+    /// Leave the cell empty
+    Empty,
+    /// Write 0
+    Zero,
+    /// Repeat the series' previous value (empty before its first sample)
+    Previous,
+    /// Write NaN
+    Nan,
+}
 
-    if let Some(auth) = &cli.auth {
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum EpochUnit {
+    /// Seconds (Prometheus)
+    S,
+    /// Milliseconds
+    Ms,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DumpFormat {
+    Json,
+    Yaml,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum AlignUnit {
+    Minute,
+    Hour,
+    Day,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TlsVersion {
+    #[value(name = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Method {
+    Get,
+    Post,
+    Auto,
+}
+
+/// Structured label matchers, assembled into a single selector
+#[derive(Args)]
+struct MatcherArgs {
+    /// Equality matcher, e.g. job=api (repeatable)
+    #[arg(long = "eq", value_name = "LABEL=VALUE", value_parser = parse_label_pair)]
+    eq: Vec<(String, String)>,
+
+    /// Inequality matcher, e.g. env=prod (repeatable)
+    #[arg(long = "ne", value_name = "LABEL=VALUE", value_parser = parse_label_pair)]
+    ne: Vec<(String, String)>,
+
+    /// Regex matcher, e.g. instance=web.* (repeatable)
+    #[arg(long = "re", value_name = "LABEL=REGEX", value_parser = parse_label_pair)]
+    re: Vec<(String, String)>,
+
+    /// Negative regex matcher, e.g. instance=db.* (repeatable)
+    #[arg(long = "nre", value_name = "LABEL=REGEX", value_parser = parse_label_pair)]
+    nre: Vec<(String, String)>,
+
+    /// File with one selector per line (blank and # comment lines are ignored)
+    #[arg(long, value_name = "PATH")]
+    match_file: Option<String>,
+
+    /// Run this instant query first and match the label sets of its result series
+    #[arg(long, value_name = "QUERY")]
+    from_query: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiResponse {
+    status: String,
+    data: Option<Value>,
+    #[serde(rename = "errorType")]
+    error_type: Option<String>,
+    error: Option<String>,
+    warnings: Option<Vec<String>>,
+    #[serde(skip)]
+    meta: ResponseMeta,
+}
+
+/// An error response from the API (`"status": "error"`). `parse_response`
+/// returns it inside its `anyhow::Error`, so callers can downcast to report it
+/// as data instead of failing.
+#[derive(Debug, Serialize)]
+struct ApiError {
+    #[serde(rename = "errorType")]
+    error_type: String,
+    error: String,
+    /// HTTP status code of the response
+    status: u16,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "API error ({}): {}", self.error_type, self.error)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Transport details of a response, reported by `--meta`.
+#[derive(Default)]
+struct ResponseMeta {
+    status: u16,
+    bytes: usize,
+    elapsed: Duration,
+}
+
+fn main() -> ExitCode {
+    let mut command = Cli::command();
+    // Env defaults are attached to the arguments before parsing, so --no-env
+    // has to be spotted ahead of clap.
+    if std::env::args_os()
+        .skip(1)
+        .take_while(|arg| arg != "--")
+        .any(|arg| arg == "--no-env")
+    {
+        command = without_env(command);
+    }
+    let matches = command.get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let color = use_color(cli.error_color, &io::stderr());
+    let result = if cli.deadline_from_context {
+        match run_cancellable(cli, matches) {
+            Ok(result) => result,
+            Err(signal) => {
+                let name = if signal == libc::SIGINT {
+                    "SIGINT"
+                } else {
+                    "SIGTERM"
+                };
+                eprintln!("cancelled by {name}");
+                return ExitCode::from(128 + signal as u8);
+            }
+        }
+    } else {
+        run(cli, &matches)
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            if color {
+                eprintln!("\x1b[1;31mError:\x1b[0m {err:?}");
+            } else {
+                eprintln!("Error: {err:?}");
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The signal that interrupted a --deadline-from-context run, or 0.
+static CANCEL_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_cancel_signal(signal: libc::c_int) {
+    CANCEL_SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+/// Runs the command on a worker thread while the main thread watches for
+/// SIGINT/SIGTERM, since a blocking request cannot be interrupted in place.
+/// Returns the signal number when the run was cancelled.
+fn run_cancellable(cli: Cli, matches: ArgMatches) -> std::result::Result<Result<()>, i32> {
+    let handler = on_cancel_signal as extern "C" fn(libc::c_int);
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+    let worker = std::thread::spawn(move || run(cli, &matches));
+    while !worker.is_finished() {
+        let signal = CANCEL_SIGNAL.load(Ordering::SeqCst);
+        if signal != 0 {
+            return Err(signal);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Ok(worker
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+}
+
+/// Whether to color a stream: `auto` colors terminals unless NO_COLOR is set.
+fn use_color(mode: ColorMode, stream: &impl IsTerminal) -> bool {
+    match mode {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => stream.is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+    }
+}
+
+/// Drops the env fallbacks of every argument, including subcommands'.
+fn without_env(command: clap::Command) -> clap::Command {
+    command
+        .mut_args(|arg| arg.env(None))
+        .mut_subcommands(without_env)
+}
+
+fn run(mut cli: Cli, matches: &ArgMatches) -> Result<()> {
+    if let Some(path) = &cli.transcript {
+        open_transcript(path)?;
+    }
+    if let Some(dir) = cli.secrets_dir.clone() {
+        load_secrets_dir(&mut cli, &dir)?;
+    }
+    if let Some(path) = &cli.headers_file {
+        let mut headers = read_list_file(path)?
+            .iter()
+            .map(|line| parse_header(line).map_err(|err| anyhow::anyhow!("{err}: {line:?}")))
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("invalid header in {path}"))?;
+        headers.retain(|(name, _)| {
+            !cli.headers
+                .iter()
+                .any(|(inline, _)| inline.eq_ignore_ascii_case(name))
+        });
+        headers.append(&mut cli.headers);
+        cli.headers = headers;
+    }
+    if cli.query_is_file
+        && let Some(query) = command_query(&mut cli.command)
+    {
+        *query = std::fs::read_to_string(&*query)
+            .with_context(|| format!("failed to read {query}"))?
+            .trim()
+            .to_string();
+    }
+    if cli.expand_at
+        && let Some(query) = command_query(&mut cli.command)
+    {
+        *query = expand_at(query, Utc::now().timestamp_millis() as f64 / 1000.0)?;
+    }
+    if cli.time_unit == EpochUnit::Ms {
+        command_times_to_secs(&mut cli.command);
+    }
+    let started = Instant::now();
+    let mut base = normalize_base(&cli, &base_url(&cli)?)?;
+    if cli.profile {
+        let resolving = Instant::now();
+        let _ = resolve_base(&base);
+        record_phase(Phase::Dns, resolving);
+    }
+    let mut builder =
+        Client::builder().user_agent(format!("prometheus-metrics/{}", env!("CARGO_PKG_VERSION")));
+    if let Some(timeout) = cli.http_timeout.or(cli.deadline) {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(timeout) = cli.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if cli.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    if cli.http1_only {
+        builder = builder.http1_only();
+    }
+    if let Some(version) = cli.tls_min_version {
+        builder = builder.min_tls_version(match version {
+            TlsVersion::Tls12 => reqwest::tls::Version::TLS_1_2,
+            TlsVersion::Tls13 => reqwest::tls::Version::TLS_1_3,
+        });
+    }
+    if let Some(sni) = &cli.tls_sni {
+        let addr = resolve_base(&base)?;
+        base.set_host(Some(sni)).context("invalid --tls-sni host")?;
+        builder = builder.resolve(sni, addr);
+    }
+    let client = builder.build().context("failed to build HTTP client")?;
+    if let Some(max_wait) = cli.wait_for_server {
+        wait_for_server(&cli, &client, &base, max_wait)?;
+    }
+
+    match &cli.command {
+        Commands::Query {
+            query,
+            time,
+            timeout,
+            method,
+            lookback_delta,
+            repeat_until,
+            interval,
+            max_wait,
+        } => {
+            let url = api_url(&base, "api/v1/query");
+            let timeout = query_timeout(&cli, matches, timeout);
+            let mut params = instant_params(query, time.as_deref(), timeout.as_deref());
+            if let Some(lookback_delta) = lookback_delta {
+                params.push(("lookback_delta".to_string(), lookback_delta.clone()));
+            }
+            let response = if *repeat_until {
+                wait_for_assertions(&cli, *interval, *max_wait, || {
+                    send_query(&cli, &client, url.clone(), params.clone(), *method)
+                })?
+            } else {
+                send_query(&cli, &client, url, params.clone(), *method)?
+            };
+            let metadata = result_metadata(&cli, &client, &base, &response)?;
+            output_data(&cli, response, &params, &metadata)?;
+        }
+
+        Commands::Range {
+            query,
+            start,
+            end,
+            step,
+            timeout,
+            method,
+            lookback_delta,
+            auto_swap,
+            max_points,
+            force,
+            align_step,
+            align_to,
+            step_scrapes,
+            auto_chunk,
+            chunk_concurrency,
+        } => {
+            let step = &match step_scrapes {
+                Some(n) => {
+                    let interval = scrape_interval(&cli, &client, &base)?;
+                    prom_duration(interval * *n)
+                }
+                None => step.clone(),
+            };
+            let (start, end) = check_range(start, end, *auto_swap)?;
+            let snapped;
+            let (start, end) = if let Some(unit) = align_to {
+                let secs = match unit {
+                    AlignUnit::Minute => 60.0,
+                    AlignUnit::Hour => 3600.0,
+                    AlignUnit::Day => 86400.0,
+                };
+                snapped = snap_range(start, end, secs)?;
+                (snapped.0.as_str(), snapped.1.as_str())
+            } else {
+                (start, end)
+            };
+            let aligned;
+            let (start, end) = if *align_step {
+                aligned = align_range(start, end, step)?;
+                (aligned.0.as_str(), aligned.1.as_str())
+            } else {
+                (start, end)
+            };
+            if !force && !auto_chunk {
+                check_points(start, end, step, *max_points)?;
+            }
+            let url = api_url(&base, "api/v1/query_range");
+            let mut params = range_params(query, start, end, step);
+            if let Some(timeout) = query_timeout(&cli, matches, timeout) {
+                params.push(("timeout".to_string(), timeout));
+            }
+            if let Some(lookback_delta) = lookback_delta {
+                params.push(("lookback_delta".to_string(), lookback_delta.clone()));
+            }
+            if cli.stream {
+                if cli.output != OutputFormat::Ndjson {
+                    bail!("--stream requires -o ndjson");
+                }
+                if *auto_chunk {
+                    bail!("--stream cannot be combined with --auto-chunk");
+                }
+                stream_ndjson(&cli, &client, url, &params, *method)?;
+            } else {
+                let response = if *auto_chunk {
+                    let chunks = chunk_range(start, end, step, *max_points)?;
+                    let responses =
+                        run_concurrent(&chunks, *chunk_concurrency as usize, |bounds| {
+                            let mut params = params.clone();
+                            for (key, value) in &mut params {
+                                match key.as_str() {
+                                    "start" => value.clone_from(&bounds.0),
+                                    "end" => value.clone_from(&bounds.1),
+                                    _ => {}
+                                }
+                            }
+                            send_query(&cli, &client, url.clone(), params, *method)
+                        })?;
+                    stitch_matrices(responses)?
+                } else {
+                    send_query(&cli, &client, url, params.clone(), *method)?
+                };
+                let metadata = result_metadata(&cli, &client, &base, &response)?;
+                output_data(&cli, response, &params, &metadata)?;
+            }
+        }
+
+        Commands::Export {
+            query,
+            start,
+            end,
+            step,
+            format,
+            out,
+        } => {
+            let (start, end) = check_range(start, end, false)?;
+            let url = api_url(&base, "api/v1/query_range");
+            let mut params = range_params(query, start, end, step);
+            if let Some(timeout) = query_timeout(&cli, matches, &None) {
+                params.push(("timeout".to_string(), timeout));
+            }
+            let response = post_form(&cli, &client, url, params)?;
+            let mut data = response.data.unwrap_or(Value::Null);
+            transform_data(&cli, &mut data);
+            let stats = export::write_matrix(&data, *format, out)?;
+            eprintln!(
+                "wrote {} samples from {} series to {out}",
+                stats.rows, stats.series
+            );
+        }
+
+        Commands::Plot {
+            query,
+            start,
+            end,
+            step,
+            out,
+            width,
+            height,
+        } => {
+            let (start, end) = check_range(start, end, false)?;
+            let url = api_url(&base, "api/v1/query_range");
+            let mut params = range_params(query, start, end, step);
+            if let Some(timeout) = query_timeout(&cli, matches, &None) {
+                params.push(("timeout".to_string(), timeout));
+            }
+            let response = post_form(&cli, &client, url, params)?;
+            let mut data = response.data.unwrap_or(Value::Null);
+            transform_data(&cli, &mut data);
+            let series = plot::render_matrix(&data, out, *width, *height)?;
+            eprintln!("plotted {series} series to {out}");
+        }
+
+        Commands::Tail {
+            query,
+            interval,
+            duration,
+            output_file,
+            output_file_rotate,
+        } => {
+            let url = api_url(&base, "api/v1/query");
+            let mut out: Box<dyn Write> = match output_file {
+                Some(path) => Box::new(RotatingFile::create(path, *output_file_rotate)?),
+                None => Box::new(io::stdout().lock()),
+            };
+            let started = Instant::now();
+            let mut last_seen: HashMap<String, Value> = HashMap::new();
+            loop {
+                let params =
+                    instant_params(query, None, query_timeout(&cli, matches, &None).as_deref());
+                match post_form(&cli, &client, url.clone(), params) {
+                    Ok(response) => {
+                        let data = response.data.unwrap_or(Value::Null);
+                        print_changes(&cli, &data, &mut last_seen, &mut out)?;
+                    }
+                    Err(err) => eprintln!("warning: {err:#}"),
+                }
+                if duration.is_some_and(|d| started.elapsed() + *interval > d) {
+                    break;
+                }
+                std::thread::sleep(*interval);
+            }
+        }
+
+        Commands::Compare {
+            query,
+            offset,
+            window,
+        } => {
+            let url = api_url(&base, "api/v1/query");
+            let query = match window {
+                Some(window) => format!("avg_over_time(({query})[{window}:])"),
+                None => query.clone(),
+            };
+            let timeout = query_timeout(&cli, matches, &None);
+            let now = Utc::now().timestamp_millis() as f64 / 1000.0;
+            let mut samples = Vec::new();
+            for time in [now - offset.as_secs_f64(), now] {
+                let params = instant_params(&query, Some(&time.to_string()), timeout.as_deref());
+                let data = post_form(&cli, &client, url.clone(), params)?
+                    .data
+                    .unwrap_or(Value::Null);
+                samples.push(vector_samples(&cli, &data)?);
+            }
+            let now = samples.pop().unwrap_or_default();
+            let before = samples.pop().unwrap_or_default();
+            print_table(
+                &["SERIES", "BEFORE", "NOW", "CHANGE"],
+                &compare_rows(&cli, &before, &now),
+            )?;
+        }
+
+        Commands::Hash { query, time } => {
+            let url = api_url(&base, "api/v1/query");
+            let timeout = query_timeout(&cli, matches, &None);
+            let params = instant_params(query, time.as_deref(), timeout.as_deref());
+            let response = post_form(&cli, &client, url, params)?;
+            let mut data = response.data.unwrap_or(Value::Null);
+            transform_data(&cli, &mut data);
+            let digest = XxHash3_128::oneshot(result_fingerprint(&cli, &data)?.as_bytes());
+            println!("{digest:032x}");
+            print_meta(&cli, &response.meta);
+        }
+
+        Commands::Batch {
+            file,
+            time,
+            timeout,
+            only_errors,
+            structured_errors,
+        } => {
+            let queries = read_list_file(file)?;
+            let url = api_url(&base, "api/v1/query");
+            let timeout = query_timeout(&cli, matches, timeout);
+
+            let mut failed = 0;
+            for query in &queries {
+                let expanded = if cli.expand_at {
+                    expand_at(query, Utc::now().timestamp_millis() as f64 / 1000.0)
+                } else {
+                    Ok(query.clone())
+                };
+                // A query with a bad `@` modifier is recorded like any other failure.
+                let (query, payload) = match expanded {
+                    Ok(query) => {
+                        let params = instant_params(&query, time.as_deref(), timeout.as_deref());
+                        let payload =
+                            post_form(&cli, &client, url.clone(), params).and_then(|response| {
+                                let empty = is_empty_result(response.data.as_ref());
+                                Ok((empty, result_payload(&cli, response)?))
+                            });
+                        (query, payload)
+                    }
+                    Err(err) => (query.clone(), Err(err)),
+                };
+                let entry = match payload {
+                    Ok((false, _)) if *only_errors => continue,
+                    Ok((_, data)) => serde_json::json!({ "query": query, "data": data }),
+                    Err(err) => {
+                        failed += 1;
+                        let error = match err.downcast_ref::<ApiError>() {
+                            Some(api) if *structured_errors => serde_json::to_value(api)?,
+                            // Failures before the server answered have no API error type.
+                            None if *structured_errors => serde_json::json!({
+                                "errorType": "client",
+                                "error": format!("{err:#}"),
+                                "status": null,
+                            }),
+                            _ => format!("{err:#}").into(),
+                        };
+                        serde_json::json!({ "query": query, "error": error })
+                    }
+                };
+                println!("{}", serde_json::to_string(&entry)?);
+            }
+            if failed > 0 {
+                bail!("{failed} of {} batch queries failed", queries.len());
+            }
+        }
+
+        Commands::CheckRules { file } => {
+            let raw =
+                std::fs::read_to_string(file).with_context(|| format!("failed to read {file}"))?;
+            let rules: RuleFile =
+                serde_yaml::from_str(&raw).with_context(|| format!("failed to parse {file}"))?;
+            let url = api_url(&base, "api/v1/format_query");
+
+            let mut rows = Vec::new();
+            let mut failed = 0;
+            for group in &rules.groups {
+                for rule in &group.rules {
+                    let name = rule
+                        .record
+                        .as_deref()
+                        .or(rule.alert.as_deref())
+                        .unwrap_or("-");
+                    let params = vec![("query".to_string(), rule.expr.clone())];
+                    let status = match post_form(&cli, &client, url.clone(), params) {
+                        Ok(_) => "ok".to_string(),
+                        Err(err) => {
+                            failed += 1;
+                            format!("{err:#}")
+                        }
+                    };
+                    rows.push(vec![group.name.clone(), name.to_string(), status]);
+                }
+            }
+            print_table(&["GROUP", "RULE", "STATUS"], &rows)?;
+            if failed > 0 {
+                bail!("{failed} of {} rules failed to parse", rows.len());
+            }
+        }
+
+        Commands::Labels {
+            label,
+            matches,
+            matchers,
+        } => {
+            let url = api_url(&base, &format!("api/v1/label/{label}/values"));
+            let matches = resolve_matches(&cli, &client, &base, matches, matchers)?;
+            let params = build_match_params(matches, None, None);
+            let response = match_query(&cli, &client, url, params)?;
+            output_list(&cli, Some(label), response)?;
+        }
+
+        Commands::LabelsCardinality {
+            matches,
+            matchers,
+            concurrency,
+        } => {
+            let url = api_url(&base, "api/v1/labels");
+            let matches = resolve_matches(&cli, &client, &base, matches, matchers)?;
+            let params = build_match_params(matches, None, None);
+            let response = match_query(&cli, &client, url, params.clone())?;
+            let names: Vec<String> = response
+                .data
+                .as_ref()
+                .and_then(Value::as_array)
+                .context("expected an array of label names")?
+                .iter()
+                .filter_map(|name| name.as_str().map(str::to_string))
+                .collect();
+
+            let counts = run_concurrent(&names, *concurrency as usize, |name| {
+                let url = api_url(&base, &format!("api/v1/label/{name}/values"));
+                let response = match_query(&cli, &client, url, params.clone())?;
+                Ok(response
+                    .data
+                    .as_ref()
+                    .and_then(Value::as_array)
+                    .map_or(0, Vec::len))
+            })?;
+
+            let mut ranked: Vec<(&String, usize)> = names.iter().zip(counts).collect();
+            ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+            let rows: Vec<Vec<String>> = ranked
+                .into_iter()
+                .map(|(name, count)| vec![name.clone(), count.to_string()])
+                .collect();
+            print_table(&["LABEL", "VALUES"], &rows)?;
+        }
+
+        Commands::Health => {
+            let url = api_url(&base, "api/v1/query");
+            let up = post_form(&cli, &client, url, instant_params("up", None, None))?;
+            let url = api_url(&base, "api/v1/targets");
+            let params = vec![("state".to_string(), "active".to_string())];
+            let targets = get_query(&cli, &client, url, params)?;
+            let mut rows = health_rows(up.data.as_ref(), targets.data.as_ref());
+            for row in &mut rows {
+                redact_value(&cli, "job", &mut row[0]);
+                redact_value(&cli, "instance", &mut row[1]);
+            }
+            print_table(&["JOB", "INSTANCE", "UP", "HEALTH", "LAST ERROR"], &rows)?;
+        }
+
+        Commands::Ready => {
+            let url = api_url(&base, "-/ready");
+            check_endpoint(&cli, client.get(url))?;
+        }
+
+        Commands::Healthy => {
+            let url = api_url(&base, "-/healthy");
+            check_endpoint(&cli, client.get(url))?;
+        }
+
+        Commands::Reload => {
+            let url = api_url(&base, "-/reload");
+            check_endpoint(&cli, client.post(url))?;
+        }
+
+        Commands::Probe => {
+            let rows = probe(&cli, &client, &base)?;
+            print_table(&["CHECK", "STATUS", "DETAIL"], &rows)?;
+            let failed = rows.iter().filter(|row| row[1] == "FAIL").count();
+            if failed > 0 {
+                bail!("{failed} of {} probe checks failed", rows.len());
+            }
+        }
+
+        Commands::ServerTime { rfc3339 } => {
+            let url = api_url(&base, "api/v1/query");
+            let response = post_form(&cli, &client, url, instant_params("time()", None, None))?;
+            let raw = response
+                .data
+                .as_ref()
+                .and_then(|data| data.pointer("/result/1"))
+                .and_then(Value::as_str)
+                .context("expected a scalar result from time()")?;
+            let secs = parse_time(raw)?;
+            let skew = secs - Utc::now().timestamp_millis() as f64 / 1000.0;
+            // Includes the request round trip, so only differences well above
+            // the response time point to clock drift.
+            log_event(
+                &cli,
+                "clock",
+                &[("skew_ms", (skew * 1000.0).round().into())],
+            );
+            if *rfc3339 {
+                let time = DateTime::from_timestamp_millis((secs * 1000.0).round() as i64)
+                    .context("server time out of range")?;
+                println!("{}", time.to_rfc3339_opts(SecondsFormat::Millis, true));
+            } else {
+                println!("{raw}");
+            }
+            print_meta(&cli, &response.meta);
+        }
+
+        Commands::BuildInfo { assert_build_after } => {
+            let url = api_url(&base, "api/v1/status/buildinfo");
+            let response = get_query(&cli, &client, url, Vec::new())?;
+            let data = response.data.unwrap_or(Value::Null);
+            emit_json(&cli, &data)?;
+            print_meta(&cli, &response.meta);
+            if let Some(threshold) = assert_build_after {
+                let raw = data
+                    .get("buildDate")
+                    .and_then(Value::as_str)
+                    .context("buildinfo has no buildDate")?;
+                let built = NaiveDateTime::parse_from_str(raw, "%Y%m%d-%H:%M:%S")
+                    .with_context(|| format!("invalid buildDate {raw:?}"))?
+                    .and_utc();
+                if built.timestamp() as f64 <= parse_time(threshold)? {
+                    bail!("server was built at {raw}, not after {threshold}");
+                }
+            }
+        }
+
+        Commands::ConfigDump { format } => {
+            let config = effective_config(&cli, matches, &base);
+            match format {
+                DumpFormat::Json => emit_json(&cli, &serde_json::to_value(&config)?)?,
+                DumpFormat::Yaml => print!("{}", serde_yaml::to_string(&config)?),
+            }
+        }
+
+        Commands::Jobs { with_counts } => {
+            let url = api_url(&base, "api/v1/label/job/values");
+            let response = get_query(&cli, &client, url, Vec::new())?;
+            if !with_counts {
+                output_list(&cli, Some("job"), response)?;
+                return Ok(());
+            }
+            let url = api_url(&base, "api/v1/query");
+            let params = instant_params("count by (job) (up)", None, None);
+            let counts = post_form(&cli, &client, url, params)?;
+            let mut rows = job_count_rows(response.data.as_ref(), counts.data.as_ref());
+            for row in &mut rows {
+                redact_value(&cli, "job", &mut row[0]);
+            }
+            print_table(&["JOB", "INSTANCES"], &rows)?;
+        }
+
+        Commands::Metrics {
+            filter,
+            metric_type,
+        } => {
+            let url = api_url(&base, "api/v1/label/__name__/values");
+            let mut response = get_query(&cli, &client, url, Vec::new())?;
+            if let Some(filter) = filter {
+                response = filter_values(response, filter)?;
+            }
+            if let Some(kind) = metric_type.and_then(|t| t.to_possible_value()) {
+                let metadata = fetch_all_metadata(&cli, &client, &base)?;
+                if let Some(Value::Array(names)) = response.data.as_mut() {
+                    names.retain(|name| {
+                        name.as_str().is_some_and(|name| {
+                            metric_family(name, &metadata)
+                                .1
+                                .is_some_and(|meta| meta.kind == kind.get_name())
+                        })
+                    });
+                }
+            }
+            output_list(&cli, Some("__name__"), response)?;
+        }
+
+        Commands::Catalog {
+            filter,
+            regex,
+            metric_type,
+        } => {
+            let url = api_url(&base, "api/v1/label/__name__/values");
+            let mut response = get_query(&cli, &client, url, Vec::new())?;
+            if let Some(filter) = filter {
+                response = filter_values(response, filter)?;
+            }
+            let metadata = fetch_all_metadata(&cli, &client, &base)?;
+            let mut rows = catalog_rows(response.data.as_ref(), regex.as_ref(), &metadata);
+            if let Some(kind) = metric_type.and_then(|t| t.to_possible_value()) {
+                rows.retain(|row| row[1] == kind.get_name());
+            }
+            print_table(&["METRIC", "TYPE", "HELP"], &rows)?;
+        }
+
+        Commands::Series {
+            matches,
+            matchers,
+            start,
+            end,
+        } => {
+            let matches = resolve_matches(&cli, &client, &base, matches, matchers)?;
+            if matches.is_empty() {
+                bail!(
+                    "--match (or --eq/--ne/--re/--nre/--from-query) is required for series queries"
+                );
+            }
+            let url = api_url(&base, "api/v1/series");
+            let params = build_match_params(matches, start.clone(), end.clone());
+            let response = match_query(&cli, &client, url, params.clone())?;
+            let metadata = result_metadata(&cli, &client, &base, &response)?;
+            output_data(&cli, response, &params, &metadata)?;
+        }
+
+        Commands::SeriesCount {
+            matches,
+            matchers,
+            start,
+            end,
+        } => {
+            let matches = resolve_matches(&cli, &client, &base, matches, matchers)?;
+            if matches.is_empty() {
+                bail!(
+                    "--match (or --eq/--ne/--re/--nre/--from-query) is required for series queries"
+                );
+            }
+            let url = api_url(&base, "api/v1/series");
+            let params = build_match_params(matches, start.clone(), end.clone());
+            let response = match_query(&cli, &client, url, params)?;
+            let count = response
+                .data
+                .as_ref()
+                .and_then(Value::as_array)
+                .context("expected an array of series")?
+                .len();
+            print_json(&count.into(), JsonStyle::Compact, !cli.no_newline)?;
+            print_meta(&cli, &response.meta);
+        }
+    }
+
+    if cli.profile {
+        print_profile(started.elapsed());
+    }
+    Ok(())
+}
+
+/// Fills unset connection settings from files in a secret mount directory.
+fn load_secrets_dir(cli: &mut Cli, dir: &str) -> Result<()> {
+    let dir = std::path::Path::new(dir);
+    if !dir.is_dir() {
+        bail!("--secrets-dir {} is not a directory", dir.display());
+    }
+    // Credential files never override an explicitly configured auth method,
+    // since a token would take precedence over it in apply_auth.
+    let has_auth = auth_scheme(cli) != "none";
+    let fields = [
+        ("base_url", &mut cli.base_url, false),
+        ("token", &mut cli.bearer, true),
+        ("username", &mut cli.user, true),
+        ("password", &mut cli.password, true),
+    ];
+    for (name, field, credential) in fields {
+        if field.is_some() || (credential && has_auth) {
+            continue;
+        }
+        let path = dir.join(name);
+        match std::fs::read_to_string(&path) {
+            Ok(value) => {
+                *field = Some(value.trim_end_matches(['\r', '\n']).to_string());
+                cli.secrets_credentials |= credential;
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Settings in effect for a run, as printed by `config-dump`. Secrets are
+/// never included; `*_source` fields say where a value came from.
+#[derive(Serialize)]
+struct EffectiveConfig {
+    base_url: String,
+    base_url_source: Option<&'static str>,
+    k8s_service: Option<String>,
+    vm_tenant: Option<String>,
+    secrets_dir: Option<String>,
+    auth_scheme: &'static str,
+    auth_source: Option<String>,
+    user: Option<String>,
+    credentials: Option<&'static str>,
+    http_timeout_secs: Option<f64>,
+    connect_timeout_secs: Option<f64>,
+    query_timeout: Option<String>,
+    http_version: &'static str,
+    tls_sni: Option<String>,
+    tls_min_version: Option<String>,
+    host_header: Option<String>,
+    /// Names only; values may carry credentials
+    headers: Vec<String>,
+    post_threshold: usize,
+    encoding: String,
+    query_params: Vec<String>,
+    retries: u32,
+    retry_backoff_secs: f64,
+    output: String,
+}
+
+fn effective_config(cli: &Cli, matches: &ArgMatches, base: &Url) -> EffectiveConfig {
+    // Values missing from the command line and environment were filled in
+    // from --secrets-dir.
+    let source = |id: &str, set: bool| match matches.value_source(id) {
+        Some(ValueSource::CommandLine) => Some("flag"),
+        Some(ValueSource::EnvVariable) => Some("env"),
+        Some(_) => Some("default"),
+        None if set => Some("secrets_dir"),
+        None => None,
+    };
+    let auth_field = [
+        ("bearer", cli.bearer.is_some()),
+        ("auth_command", cli.auth_command.is_some()),
+        ("auth", cli.auth.is_some()),
+        ("auth_file", cli.auth_file.is_some()),
+        ("user", cli.user.is_some()),
+        ("password", cli.password.is_some()),
+    ]
+    .into_iter()
+    .find(|(_, set)| *set);
+    let user = match (&cli.auth, &cli.user) {
+        (Some(auth), _) => split_auth(auth).ok().map(|(user, _)| user),
+        (None, user) => user.clone(),
+    };
+    let value_name = |value: Option<clap::builder::PossibleValue>| {
+        value.map_or_else(String::new, |value| value.get_name().to_string())
+    };
+    let mut base = base.clone();
+    let _ = base.set_username("");
+    let _ = base.set_password(None);
+    EffectiveConfig {
+        base_url: base.to_string(),
+        base_url_source: match &cli.k8s_service {
+            Some(_) => source("k8s_service", true),
+            None => source("base_url", cli.base_url.is_some()),
+        },
+        k8s_service: cli.k8s_service.clone(),
+        vm_tenant: cli.vm_tenant.clone(),
+        secrets_dir: cli.secrets_dir.clone(),
+        auth_scheme: auth_scheme(cli),
+        auth_source: auth_field.map(|(id, set)| {
+            format!(
+                "--{} ({})",
+                id.replace('_', "-"),
+                source(id, set).unwrap_or_default()
+            )
+        }),
+        user: user.filter(|_| auth_scheme(cli) == "basic"),
+        credentials: auth_field.map(|_| REDACTED),
+        http_timeout_secs: cli.http_timeout.or(cli.deadline).map(|d| d.as_secs_f64()),
+        connect_timeout_secs: cli.connect_timeout.map(|d| d.as_secs_f64()),
+        query_timeout: query_timeout(cli, matches, &None)
+            .or_else(|| std::env::var("PROMQL_TIMEOUT").ok().filter(|_| !cli.no_env)),
+        http_version: if cli.http2_prior_knowledge {
+            "http2"
+        } else if cli.http1_only {
+            "http1"
+        } else {
+            "auto"
+        },
+        tls_sni: cli.tls_sni.clone(),
+        tls_min_version: cli
+            .tls_min_version
+            .and_then(|version| version.to_possible_value())
+            .map(|value| value.get_name().to_string()),
+        host_header: cli.host_header.clone(),
+        headers: cli.headers.iter().map(|(name, _)| name.clone()).collect(),
+        post_threshold: cli.post_threshold,
+        encoding: value_name(cli.encoding.to_possible_value()),
+        query_params: cli
+            .query_params
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect(),
+        retries: cli.retries,
+        retry_backoff_secs: cli.retry_backoff.as_secs_f64(),
+        output: value_name(cli.output.to_possible_value()),
+    }
+}
+
+fn base_url(cli: &Cli) -> Result<String> {
+    let base = match &cli.k8s_service {
+        Some(service) => k8s_service_url(service)?,
+        None => cli.base_url.clone().context("--base-url is required")?,
+    };
+    match &cli.vm_tenant {
+        Some(tenant) => Ok(format!(
+            "{}/select/{tenant}/prometheus/",
+            base.trim_end_matches('/')
+        )),
+        None => Ok(base),
+    }
+}
+
+fn parse_vm_tenant(raw: &str) -> Result<String, String> {
+    let valid = |id: &str| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit());
+    match raw.split_once(':') {
+        Some((account, project)) if valid(account) && valid(project) => Ok(raw.to_string()),
+        None if valid(raw) => Ok(raw.to_string()),
+        _ => Err("expected ACCOUNT or ACCOUNT:PROJECT with numeric IDs".to_string()),
+    }
+}
+
+/// Expands `prometheus.monitoring:9090` to `http://prometheus.monitoring.svc:9090`,
+/// keeping any explicit scheme, port and path.
+fn k8s_service_url(service: &str) -> Result<String> {
+    let (scheme, rest) = service.split_once("://").unwrap_or(("http", service));
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (authority, None),
+    };
+    let labels: Vec<&str> = host.split('.').collect();
+    if labels.len() < 2 || labels.iter().any(|label| label.is_empty()) {
+        bail!("--k8s-service must be in the form service.namespace[:port], got {service:?}");
+    }
+    let host = if host.ends_with(".svc") || host.contains(".svc.") {
+        host.to_string()
+    } else {
+        format!("{host}.svc")
+    };
+    let port = port.map(|port| format!(":{port}")).unwrap_or_default();
+    Ok(format!("{scheme}://{host}{port}{path}"))
+}
+
+/// The global `scrape_interval` from /api/v1/status/config, else the most
+/// common interval of the active targets (for servers without that endpoint).
+fn scrape_interval(cli: &Cli, client: &Client, base: &Url) -> Result<Duration> {
+    let url = api_url(base, "api/v1/status/config");
+    let configured = get_query(cli, client, url, Vec::new())
+        .ok()
+        .and_then(|response| {
+            let yaml = response.data?.get("yaml")?.as_str()?.to_string();
+            let config: serde_yaml::Value = serde_yaml::from_str(&yaml).ok()?;
+            config["global"]["scrape_interval"]
+                .as_str()
+                .map(str::to_string)
+        });
+    if let Some(interval) = configured {
+        return parse_duration(&interval)
+            .with_context(|| format!("invalid scrape_interval {interval:?}"));
+    }
+
+    let url = api_url(base, "api/v1/targets");
+    let params = vec![("state".to_string(), "active".to_string())];
+    let targets = get_query(cli, client, url, params)
+        .context("could not determine the scrape interval for --step-scrapes")?;
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for target in targets
+        .data
+        .as_ref()
+        .and_then(|data| data.get("activeTargets"))
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        if let Some(interval) = target.get("scrapeInterval").and_then(Value::as_str) {
+            *counts.entry(interval).or_default() += 1;
+        }
+    }
+    let Some((interval, _)) = counts.into_iter().max_by_key(|(_, count)| *count) else {
+        bail!(
+            "could not determine the scrape interval for --step-scrapes: \
+             no scrape_interval in /api/v1/status/config and no active targets report one"
+        );
+    };
+    parse_duration(interval).with_context(|| format!("invalid scrapeInterval {interval:?}"))
+}
+
+/// Resolves the base URL's host and port, so that --tls-sni can keep
+/// connecting there while presenting a different hostname.
+fn resolve_base(base: &Url) -> Result<SocketAddr> {
+    let host = base.host_str().context("base URL has no host")?;
+    let port = base
+        .port_or_known_default()
+        .context("base URL has no port")?;
+    (host.trim_start_matches('[').trim_end_matches(']'), port)
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve {host}"))?
+        .next()
+        .with_context(|| format!("no addresses found for {host}"))
+}
+
+/// Parses the base URL, appending a trailing slash (unless --no-normalize-base)
+/// so API paths are joined below its path.
+fn normalize_base(cli: &Cli, base: &str) -> Result<Url> {
+    let mut base = base.to_string();
+    if !cli.no_normalize_base && !base.ends_with('/') {
+        base.push('/');
+    }
+    let url = Url::parse(&base).context("invalid base URL")?;
+    check_vm_tenant_path(&url)?;
+    Ok(url)
+}
+
+/// Appends `path` below the base URL's path, inserting a `/` when the base
+/// has none, so a path prefix is always kept (`Url::join` would replace the
+/// last segment of a base without a trailing slash). The base URL's query and
+/// fragment are dropped.
+fn api_url(base: &Url, path: &str) -> Url {
+    let mut url = base.clone();
+    let prefix = base.path().trim_end_matches('/');
+    url.set_path(&format!("{prefix}/{path}"));
+    url.set_query(None);
+    url.set_fragment(None);
+    url
+}
+
+/// Rejects VictoriaMetrics cluster URLs that stop at `/select/<tenant>`, which
+/// would send API calls to vmselect's non-Prometheus handlers.
+fn check_vm_tenant_path(url: &Url) -> Result<()> {
+    let segments: Vec<&str> = url.path_segments().into_iter().flatten().collect();
+    for (i, segment) in segments.iter().enumerate() {
+        let Some(tenant) = segments.get(i + 1) else {
+            break;
+        };
+        if *segment == "select"
+            && parse_vm_tenant(tenant).is_ok()
+            && segments.get(i + 2) != Some(&"prometheus")
+        {
+            bail!(
+                "VictoriaMetrics cluster URL {url} must end in /select/{tenant}/prometheus \
+                 (or pass --vm-tenant {tenant} with the vmselect root URL)"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Parses an RFC3339 or Unix timestamp into seconds since the epoch.
+fn parse_time(raw: &str) -> Result<f64> {
+    if let Ok(secs) = raw.parse::<f64>() {
+        return Ok(secs);
+    }
+    let time = DateTime::parse_from_rfc3339(raw)
+        .with_context(|| format!("invalid time {raw:?}: expected RFC3339 or Unix timestamp"))?;
+    Ok(time.timestamp() as f64 + f64::from(time.timestamp_subsec_nanos()) / 1e9)
+}
+
+/// Converts numeric millisecond --time/--start/--end values (--time-unit ms)
+/// to the seconds the API expects; RFC3339 values are left as they are.
+fn command_times_to_secs(command: &mut Commands) {
+    let convert = |raw: &mut String| {
+        if let Ok(ms) = raw.parse::<f64>() {
+            *raw = (ms / 1000.0).to_string();
+        }
+    };
+    match command {
+        Commands::Query { time, .. }
+        | Commands::Hash { time, .. }
+        | Commands::Batch { time, .. } => {
+            time.iter_mut().for_each(convert);
+        }
+        Commands::Range { start, end, .. }
+        | Commands::Export { start, end, .. }
+        | Commands::Plot { start, end, .. } => {
+            convert(start);
+            convert(end);
+        }
+        Commands::Series { start, end, .. } | Commands::SeriesCount { start, end, .. } => {
+            start.iter_mut().chain(end.iter_mut()).for_each(convert);
+        }
+        _ => {}
+    }
+}
+
+/// The query of commands that take a single one, for --query-is-file and
+/// --expand-at.
+fn command_query(command: &mut Commands) -> Option<&mut String> {
+    match command {
+        Commands::Query { query, .. }
+        | Commands::Hash { query, .. }
+        | Commands::Range { query, .. }
+        | Commands::Export { query, .. }
+        | Commands::Plot { query, .. }
+        | Commands::Tail { query, .. }
+        | Commands::Compare { query, .. } => Some(query),
+        _ => None,
+    }
+}
+
+/// Rewrites `@ now`, `@ now-DUR`, `@ now+DUR` and `@ <RFC3339>` modifiers to
+/// Unix timestamps. Numeric timestamps, `start()`/`end()` and anything inside
+/// string literals are left as they are.
+fn expand_at(query: &str, now: f64) -> Result<String> {
+    let mut out = String::with_capacity(query.len());
+    let mut quote = None;
+    let mut chars = query.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        out.push(c);
+        match (quote, c) {
+            (Some(q), '\\') if q != '`' => {
+                if let Some((_, escaped)) = chars.next() {
+                    out.push(escaped);
+                }
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'' | '`') => quote = Some(c),
+            (None, '@') => {
+                let rest = &query[i + 1..];
+                let token_start = rest.len() - rest.trim_start().len();
+                let token = rest[token_start..]
+                    .split(|c: char| !(c.is_ascii_alphanumeric() || "-+:._".contains(c)))
+                    .next()
+                    .unwrap_or_default();
+                let Some(secs) = resolve_at(token, now)
+                    .with_context(|| format!("invalid @ modifier {token:?}"))?
+                else {
+                    continue;
+                };
+                out.push_str(&rest[..token_start]);
+                out.push_str(&secs.to_string());
+                for _ in 0..token_start + token.len() {
+                    chars.next();
+                }
+            }
+            (None, _) => {}
+        }
+    }
+    Ok(out)
+}
+
+/// The timestamp an `@` token stands for, or `None` when it should be kept.
+fn resolve_at(token: &str, now: f64) -> Result<Option<f64>> {
+    if let Some(offset) = token.strip_prefix("now") {
+        let secs = match offset.split_at_checked(1) {
+            None => now,
+            Some(("-", duration)) => now - parse_duration(duration)?.as_secs_f64(),
+            Some(("+", duration)) => now + parse_duration(duration)?.as_secs_f64(),
+            Some(_) => return Ok(None),
+        };
+        return Ok(Some(secs));
+    }
+    if token.is_empty() || token.parse::<f64>().is_ok() {
+        return Ok(None);
+    }
+    match DateTime::parse_from_rfc3339(token) {
+        Ok(_) => parse_time(token).map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+fn check_range<'a>(start: &'a str, end: &'a str, auto_swap: bool) -> Result<(&'a str, &'a str)> {
+    let start_secs = parse_time(start).context("invalid --start")?;
+    let end_secs = parse_time(end).context("invalid --end")?;
+    if start_secs < end_secs {
+        return Ok((start, end));
+    }
+    if auto_swap && start_secs > end_secs {
+        eprintln!("warning: --start is after --end, swapping them");
+        return Ok((end, start));
+    }
+    bail!("--start ({start}) must be before --end ({end})");
+}
+
+/// Renders `duration` the way Prometheus parses durations: whole seconds,
+/// or whole milliseconds when there is a fraction (`1.5s` is rejected).
+fn prom_duration(duration: Duration) -> String {
+    if duration.subsec_millis() == 0 {
+        format!("{}s", duration.as_secs())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
+/// Parses a Prometheus duration (e.g. `90s`, `1h30m`) or a plain number of seconds.
+fn parse_duration(raw: &str) -> Result<Duration> {
+    if raw.is_empty() {
+        bail!("invalid duration: empty string");
+    }
+    if let Ok(secs) = raw.parse::<f64>() {
+        if secs.is_finite() && secs >= 0.0 {
+            return Ok(Duration::from_secs_f64(secs));
+        }
+        bail!("invalid duration {raw:?}");
+    }
+
+    let mut total = 0.0;
+    let mut rest = raw;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let units = rest[digits..]
+            .find(|c: char| c.is_ascii_digit())
+            .map_or(rest.len(), |i| digits + i);
+        if digits == 0 || digits == units {
+            bail!("invalid duration {raw:?}");
+        }
+        let value: f64 = rest[..digits].parse()?;
+        let unit_secs = match &rest[digits..units] {
+            "ms" => 0.001,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            "d" => 86400.0,
+            "w" => 604800.0,
+            "y" => 31536000.0,
+            unit => bail!("invalid duration {raw:?}: unknown unit {unit:?}"),
+        };
+        total += value * unit_secs;
+        rest = &rest[units..];
+    }
+    Ok(Duration::from_secs_f64(total))
+}
+
+fn check_points(start: &str, end: &str, step: &str, max_points: u64) -> Result<()> {
+    let step_secs = parse_duration(step)
+        .context("invalid --step")?
+        .as_secs_f64();
+    if step_secs <= 0.0 {
+        bail!("--step must be greater than zero");
+    }
+    let span = parse_time(end)? - parse_time(start)?;
+    let points = (span / step_secs).floor() as u64 + 1;
+    if points > max_points {
+        bail!(
+            "range query would return {points} points per series (max {max_points}); \
+             increase --step, shorten the range, or pass --force"
+        );
+    }
+    Ok(())
+}
+
+/// Splits `[start, end]` into consecutive windows on the step grid with at
+/// most `max_points` points each.
+fn chunk_range(
+    start: &str,
+    end: &str,
+    step: &str,
+    max_points: u64,
+) -> Result<Vec<(String, String)>> {
+    let step_secs = parse_duration(step)
+        .context("invalid --step")?
+        .as_secs_f64();
+    if step_secs <= 0.0 {
+        bail!("--step must be greater than zero");
+    }
+    let (start, end) = (parse_time(start)?, parse_time(end)?);
+    let span = max_points.saturating_sub(1).max(1) as f64 * step_secs;
+    let mut chunks = Vec::new();
+    let mut chunk_start = start;
+    while chunk_start <= end {
+        let chunk_end = (chunk_start + span).min(end);
+        chunks.push((chunk_start.to_string(), chunk_end.to_string()));
+        chunk_start = chunk_end + step_secs;
+    }
+    Ok(chunks)
+}
+
+/// Joins the matrix results of consecutive range chunks, series by series.
+fn stitch_matrices(responses: Vec<ApiResponse>) -> Result<ApiResponse> {
+    let mut series: Vec<(Value, Vec<Value>)> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut meta = ResponseMeta::default();
+    for response in responses {
+        meta.status = response.meta.status;
+        meta.bytes += response.meta.bytes;
+        meta.elapsed = meta.elapsed.max(response.meta.elapsed);
+        let data = response.data.unwrap_or(Value::Null);
+        if data.get("resultType").and_then(Value::as_str) != Some("matrix") {
+            bail!("--auto-chunk expects matrix results");
+        }
+        let Some(Value::Array(results)) = data.get("result").cloned() else {
+            continue;
+        };
+        for mut item in results {
+            let metric = item.get_mut("metric").map(Value::take).unwrap_or_default();
+            let values = match item.get_mut("values").map(Value::take) {
+                Some(Value::Array(values)) => values,
+                _ => Vec::new(),
+            };
+            let i = *index.entry(metric.to_string()).or_insert_with(|| {
+                series.push((metric, Vec::new()));
+                series.len() - 1
+            });
+            series[i].1.extend(values);
+        }
+    }
+    let result = series
+        .into_iter()
+        .map(|(metric, values)| serde_json::json!({ "metric": metric, "values": values }))
+        .collect();
+    Ok(ApiResponse {
+        status: "success".to_string(),
+        data: Some(serde_json::json!({ "resultType": "matrix", "result": Value::Array(result) })),
+        error_type: None,
+        error: None,
+        warnings: None,
+        meta,
+    })
+}
+
+/// Rounds `start` down and `end` up to multiples of `step`, as Unix timestamps.
+fn align_range(start: &str, end: &str, step: &str) -> Result<(String, String)> {
+    let step_secs = parse_duration(step)
+        .context("invalid --step")?
+        .as_secs_f64();
+    if step_secs <= 0.0 {
+        bail!("--step must be greater than zero");
+    }
+    snap_range(start, end, step_secs)
+}
+
+/// Floors start and ceils end to multiples of `unit` seconds. Unix time has
+/// no offset, so hour and day multiples are UTC boundaries whatever offset
+/// an RFC3339 input was written with.
+fn snap_range(start: &str, end: &str, unit: f64) -> Result<(String, String)> {
+    let start = (parse_time(start)? / unit).floor() * unit;
+    let end = (parse_time(end)? / unit).ceil() * unit;
+    Ok((start.to_string(), end.to_string()))
+}
+
+/// Adds the --header/--headers-file headers and the --host-header override.
+fn apply_headers(mut request: RequestBuilder, cli: &Cli) -> RequestBuilder {
+    for (name, value) in &cli.headers {
+        request = request.header(name, value);
+    }
+    if let Some(host) = &cli.host_header {
+        request = request.header(HOST, host);
+    }
+    request
+}
+
+fn apply_auth(request: RequestBuilder, cli: &Cli) -> Result<RequestBuilder> {
+    if let Some(token) = &cli.bearer {
+        return Ok(request.bearer_auth(token));
+    }
+
+    if let Some(command) = &cli.auth_command {
+        return Ok(request.bearer_auth(command_token(command, cli.auth_command_timeout)?));
+    }
+
+    if let Some(auth) = &cli.auth {
         let (user, pass) = split_auth(auth)?;
         return Ok(request.basic_auth(user, Some(pass)));
     }
 
-    if cli.user.is_some() || cli.password.is_some() {
-        let user = cli
-            .user
-            .as_ref()
-            .context("--user is required when using --password")?
-            .to_string();
-        let pass = cli
-            .password
-            .as_ref()
-            .context("--password is required when using --user")?
-            .to_string();
-        return Ok(request.basic_auth(user, Some(pass)));
+    if let Some(path) = &cli.auth_file {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read --auth-file {path}"))?;
+        let (user, pass) = split_auth(raw.trim_end_matches(['\r', '\n']))
+            .with_context(|| format!("invalid --auth-file {path}"))?;
+        return Ok(request.basic_auth(user, Some(pass)));
+    }
+
+    if cli.user.is_some() || cli.password.is_some() {
+        let user = cli
+            .user
+            .as_ref()
+            .context("--user is required when using --password")?
+            .to_string();
+        let pass = cli
+            .password
+            .as_ref()
+            .context("--password is required when using --user")?
+            .to_string();
+        return Ok(request.basic_auth(user, Some(pass)));
+    }
+
+    Ok(request)
+}
+
+/// Stages of a run timed by --profile.
+#[derive(Clone, Copy)]
+enum Phase {
+    Dns,
+    Request,
+    Body,
+    Parse,
+    Output,
+}
+
+/// Time accumulated per [`Phase`] across all requests of the run.
+static PROFILE: Mutex<[Duration; 5]> = Mutex::new([Duration::ZERO; 5]);
+
+fn record_phase(phase: Phase, started: Instant) {
+    let mut profile = PROFILE.lock().unwrap_or_else(|err| err.into_inner());
+    profile[phase as usize] += started.elapsed();
+}
+
+/// Prints the --profile report; time not covered by a phase (argument
+/// parsing, auth commands, sleeps between polls) is reported as `other`.
+fn print_profile(total: Duration) {
+    let profile = *PROFILE.lock().unwrap_or_else(|err| err.into_inner());
+    let labels = [
+        "dns",
+        "request (connect + headers)",
+        "body read",
+        "json parse",
+        "output",
+    ];
+    eprintln!("profile:");
+    for (label, elapsed) in labels.iter().zip(profile) {
+        eprintln!("  {label:<28}{:>10.1}ms", duration_ms(elapsed));
+    }
+    let other = total.saturating_sub(profile.iter().sum());
+    eprintln!("  {:<28}{:>10.1}ms", "other", duration_ms(other));
+    eprintln!("  {:<28}{:>10.1}ms", "total", duration_ms(total));
+}
+
+/// File opened by --transcript; entries are only written once it is set.
+static TRANSCRIPT: Mutex<Option<std::fs::File>> = Mutex::new(None);
+
+fn open_transcript(path: &str) -> Result<()> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open --transcript {path}"))?;
+    *TRANSCRIPT.lock().unwrap_or_else(|err| err.into_inner()) = Some(file);
+    Ok(())
+}
+
+/// Appends one `{timestamp, event, ...fields}` line to the --transcript file.
+fn write_transcript(event: &str, fields: &[(&str, Value)]) -> Result<()> {
+    let mut transcript = TRANSCRIPT.lock().unwrap_or_else(|err| err.into_inner());
+    let Some(file) = transcript.as_mut() else {
+        return Ok(());
+    };
+    let mut line = serde_json::Map::new();
+    line.insert(
+        "timestamp".to_string(),
+        Utc::now()
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+            .into(),
+    );
+    line.insert("event".to_string(), event.into());
+    for (key, value) in fields {
+        line.insert(key.to_string(), value.clone());
+    }
+    writeln!(file, "{}", Value::Object(line)).context("failed to write --transcript")
+}
+
+/// Drops any user:password from a URL before it is written anywhere.
+fn strip_credentials(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut url) => {
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            url.to_string()
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+/// Token printed by --auth-command, cached for the lifetime of the process.
+static COMMAND_TOKEN: Mutex<Option<String>> = Mutex::new(None);
+
+/// Runs `command` through `sh -c` (once) and returns its trimmed stdout.
+fn command_token(command: &str, timeout: Duration) -> Result<String> {
+    let mut cached = COMMAND_TOKEN.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(token) = cached.as_ref() {
+        return Ok(token.clone());
+    }
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("failed to run --auth-command")?;
+    let mut stdout = child
+        .stdout
+        .take()
+        .context("failed to capture --auth-command output")?;
+    let reader = std::thread::spawn(move || {
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).map(|_| output)
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("--auth-command timed out after {}s", timeout.as_secs_f64());
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    if !status.success() {
+        bail!("--auth-command failed ({status})");
+    }
+    let output = reader
+        .join()
+        .map_err(|_| anyhow::anyhow!("failed to read --auth-command output"))?
+        .context("failed to read --auth-command output")?;
+    let token = output.trim();
+    if token.is_empty() {
+        bail!("--auth-command printed no token");
+    }
+    Ok(cached.insert(token.to_string()).clone())
+}
+
+/// The auth scheme `apply_auth` will use, following the same precedence.
+fn auth_scheme(cli: &Cli) -> &'static str {
+    if cli.bearer.is_some() || cli.auth_command.is_some() {
+        "bearer"
+    } else if cli.auth.is_some()
+        || cli.auth_file.is_some()
+        || cli.user.is_some()
+        || cli.password.is_some()
+    {
+        "basic"
+    } else {
+        "none"
+    }
+}
+
+fn split_auth(auth: &str) -> Result<(String, String)> {
+    let mut parts = auth.splitn(2, ':');
+    let user = parts.next().unwrap_or_default();
+    let pass = parts.next();
+    if user.is_empty() || pass.is_none() {
+        bail!("auth must be in the form user:password");
+    }
+    Ok((user.to_string(), pass.unwrap().to_string()))
+}
+
+/// Sends a query endpoint request with the chosen method. In auto mode a POST
+/// rejected with 405 Method Not Allowed is retried as GET with the same params.
+/// Transient failures (and empty results with --retry-on-empty) are retried
+/// up to --retries times and client-side timeouts up to --timeout-retries
+/// times, each with its own exponential backoff.
+fn send_query(
+    cli: &Cli,
+    client: &Client,
+    url: Url,
+    params: Vec<(String, String)>,
+    method: Method,
+) -> Result<ApiResponse> {
+    let (mut retries, mut timeout_retries) = (0, 0);
+    let (mut backoff, mut timeout_backoff) = (cli.retry_backoff, cli.timeout_retry_backoff);
+    loop {
+        let retry = retries < cli.retries;
+        let retry_timeout = timeout_retries < cli.timeout_retries;
+        let started = Instant::now();
+        let (timed_out, reason) =
+            match send_query_request(cli, client, url.clone(), &params, method) {
+                Err(err) if retry_timeout && is_request_timeout(&err) => (true, format!("{err:#}")),
+                Err(err)
+                    if retry
+                        && !is_request_timeout(&err)
+                        && err.downcast_ref::<reqwest::Error>().is_some() =>
+                {
+                    (false, format!("{err:#}"))
+                }
+                Err(err) => return Err(err),
+                Ok(response) if retry && response.status().is_server_error() => {
+                    (false, format!("HTTP {}", response.status()))
+                }
+                Ok(response) => match parse_response(cli, response, started) {
+                    // The body of a slow query can also time out while it is read.
+                    Err(err) if retry_timeout && is_request_timeout(&err) => {
+                        (true, format!("{err:#}"))
+                    }
+                    Ok(parsed)
+                        if retry && cli.retry_on_empty && is_empty_result(parsed.data.as_ref()) =>
+                    {
+                        (false, "empty result".to_string())
+                    }
+                    outcome => return outcome,
+                },
+            };
+        let (attempt, cap, delay) = if timed_out {
+            timeout_retries += 1;
+            let delay = timeout_backoff;
+            timeout_backoff *= 2;
+            (timeout_retries, cli.timeout_retries, delay)
+        } else {
+            retries += 1;
+            let delay = backoff;
+            backoff *= 2;
+            (retries, cli.retries, delay)
+        };
+        eprintln!(
+            "retrying in {}s ({attempt}/{cap}): {reason}",
+            delay.as_secs_f64()
+        );
+        std::thread::sleep(delay);
+    }
+}
+
+/// Whether `err` is a client-side timeout waiting for the server, as opposed
+/// to a failure to connect.
+fn is_request_timeout(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .any(|err| err.is_timeout() && !err.is_connect())
+}
+
+/// Like [`send_query`], but returns the response before its body is read.
+fn send_query_request(
+    cli: &Cli,
+    client: &Client,
+    url: Url,
+    params: &[(String, String)],
+    method: Method,
+) -> Result<Response> {
+    match method {
+        Method::Get => send_get(cli, client, url, params),
+        Method::Post => send_form(cli, client, url, params),
+        Method::Auto if encode_params(cli, params).len() <= cli.post_threshold => {
+            send_get(cli, client, url, params)
+        }
+        Method::Auto => {
+            let response = send_form(cli, client, url.clone(), params)?;
+            if response.status() == StatusCode::METHOD_NOT_ALLOWED {
+                return send_get(cli, client, url, params);
+            }
+            Ok(response)
+        }
+    }
+}
+
+fn post_form(
+    cli: &Cli,
+    client: &Client,
+    url: Url,
+    params: Vec<(String, String)>,
+) -> Result<ApiResponse> {
+    let started = Instant::now();
+    let response = send_form(cli, client, url, &params)?;
+    parse_response(cli, response, started)
+}
+
+fn send_form(
+    cli: &Cli,
+    client: &Client,
+    url: Url,
+    params: &[(String, String)],
+) -> Result<Response> {
+    let body = encode_params(cli, params);
+    let request = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded");
+    let request = if cli.compress_request {
+        request
+            .header(CONTENT_ENCODING, "gzip")
+            .body(gzip_body(&body)?)
+    } else {
+        request.body(body)
+    };
+    send(cli, request)
+}
+
+fn gzip_body(body: &str) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body.as_bytes())?;
+    encoder.finish().context("failed to compress request body")
+}
+
+/// Characters left as-is by `--encoding query` (RFC 3986 unreserved).
+const QUERY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Encodes `params` followed by any --query-param pairs.
+fn encode_params(cli: &Cli, params: &[(String, String)]) -> String {
+    let params = params.iter().chain(&cli.query_params);
+    match cli.encoding {
+        Encoding::Form => url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params)
+            .finish(),
+        Encoding::Query => params
+            .map(|(key, value)| {
+                format!(
+                    "{}={}",
+                    utf8_percent_encode(key, QUERY_ENCODE_SET),
+                    utf8_percent_encode(value, QUERY_ENCODE_SET)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("&"),
+    }
+}
+
+/// Sends a `match[]` list request like `get_query`, but switches to POST
+/// above --post-threshold (as query/range do) so long selector lists do not
+/// hit URL length limits. Endpoints without POST support answer 405, which
+/// falls back to GET.
+fn match_query(
+    cli: &Cli,
+    client: &Client,
+    url: Url,
+    params: Vec<(String, String)>,
+) -> Result<ApiResponse> {
+    let started = Instant::now();
+    let response = send_query_request(cli, client, url, &params, Method::Auto)?;
+    parse_response(cli, response, started)
+}
+
+fn get_query(
+    cli: &Cli,
+    client: &Client,
+    url: Url,
+    params: Vec<(String, String)>,
+) -> Result<ApiResponse> {
+    let started = Instant::now();
+    let response = send_get(cli, client, url, &params)?;
+    parse_response(cli, response, started)
+}
+
+fn send_get(cli: &Cli, client: &Client, url: Url, params: &[(String, String)]) -> Result<Response> {
+    let mut url = url;
+    url.set_query(Some(&encode_params(cli, params)));
+    send(cli, client.get(url))
+}
+
+/// Applies auth and headers and sends the request, logging both ends when
+/// --verbose is set.
+fn send(cli: &Cli, request: RequestBuilder) -> Result<Response> {
+    let request = apply_headers(apply_auth(request, cli)?, cli);
+    let (client, request) = request.build_split();
+    let request = request.context("failed to build request")?;
+    let method = request.method().to_string();
+    let url = request.url().to_string();
+    if cli.transcript.is_some() {
+        let headers: serde_json::Map<String, Value> = request
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                // --header values may carry credentials (API keys, tenant tokens).
+                let custom = cli
+                    .headers
+                    .iter()
+                    .any(|(custom, _)| custom.eq_ignore_ascii_case(name.as_str()));
+                let value = match *name {
+                    AUTHORIZATION | PROXY_AUTHORIZATION | COOKIE => REDACTED.into(),
+                    _ if custom => REDACTED.into(),
+                    _ => String::from_utf8_lossy(value.as_bytes()).into(),
+                };
+                (name.to_string(), value)
+            })
+            .collect();
+        let body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(|bytes| match std::str::from_utf8(bytes) {
+                Ok(text) => text.to_string(),
+                Err(_) => format!("<{} bytes binary>", bytes.len()),
+            });
+        write_transcript(
+            "request",
+            &[
+                ("method", method.as_str().into()),
+                ("url", strip_credentials(&url).into()),
+                ("headers", headers.into()),
+                ("body", body.into()),
+            ],
+        )?;
+    }
+    log_event(
+        cli,
+        "request",
+        &[
+            ("method", method.as_str().into()),
+            ("url", url.as_str().into()),
+        ],
+    );
+
+    let started = Instant::now();
+    let response = client
+        .execute(request)
+        .map_err(|err| request_error(cli, err));
+    record_phase(Phase::Request, started);
+    let response = match response {
+        Ok(response) => response,
+        Err(err) => {
+            write_transcript(
+                "error",
+                &[
+                    ("url", strip_credentials(&url).into()),
+                    ("error", format!("{err:#}").into()),
+                    ("duration_ms", duration_ms(started.elapsed()).into()),
+                ],
+            )?;
+            return Err(err);
+        }
+    };
+    write_transcript(
+        "response",
+        &[
+            ("url", strip_credentials(&url).into()),
+            ("status", response.status().as_u16().into()),
+            ("duration_ms", duration_ms(started.elapsed()).into()),
+        ],
+    )?;
+    log_event(
+        cli,
+        "response",
+        &[
+            ("url", url.into()),
+            ("status", response.status().as_u16().into()),
+            ("duration_ms", duration_ms(started.elapsed()).into()),
+        ],
+    );
+    Ok(response)
+}
+
+/// Sends a request to a plain-text management endpoint such as `/-/ready`,
+/// printing the status and (a preview of) the body and failing on a non-2xx status.
+fn check_endpoint(cli: &Cli, request: RequestBuilder) -> Result<()> {
+    let response = send(cli, request)?;
+    let status = response.status();
+    let url = response.url().clone();
+    let body = response.text().context("failed to read response body")?;
+    let body = match body.trim().char_indices().nth(200) {
+        Some((end, _)) => format!("{}...", &body.trim()[..end]),
+        None => body.trim().to_string(),
+    };
+    if body.is_empty() {
+        println!("{}", status.as_u16());
+    } else {
+        println!("{} {body}", status.as_u16());
+    }
+    if !status.is_success() {
+        bail!("{url} returned HTTP {status}");
+    }
+    Ok(())
+}
+
+/// Polls `/-/ready` until it returns 2xx, switching to
+/// `/api/v1/status/buildinfo` for servers that do not serve it (404).
+fn wait_for_server(cli: &Cli, client: &Client, base: &Url, max_wait: Duration) -> Result<()> {
+    let mut url = api_url(base, "-/ready");
+    let started = Instant::now();
+    loop {
+        let outcome = send(cli, client.get(url.clone()));
+        match &outcome {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+                let fallback = api_url(base, "api/v1/status/buildinfo");
+                if url != fallback {
+                    url = fallback;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        if started.elapsed() >= max_wait {
+            let reason = match outcome {
+                Ok(response) => format!("HTTP {}", response.status()),
+                Err(err) => format!("{err:#}"),
+            };
+            bail!(
+                "server not ready after {}s: {reason}",
+                max_wait.as_secs_f64()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// What to check when the server rejects a request with 401/403, naming
+/// the source of the credential that `apply_auth` sent.
+fn auth_hint(cli: &Cli) -> &'static str {
+    if cli.bearer.is_some() {
+        if cli.secrets_credentials {
+            "a bearer token was sent; check the token file in --secrets-dir"
+        } else {
+            "a bearer token was sent; check --bearer"
+        }
+    } else if cli.auth_command.is_some() {
+        "a bearer token was sent; check the output of --auth-command"
+    } else if cli.auth.is_some() {
+        "basic auth was sent; check --auth"
+    } else if cli.auth_file.is_some() {
+        "basic auth was sent; check the credentials in --auth-file"
+    } else if cli.user.is_some() || cli.password.is_some() {
+        if cli.secrets_credentials {
+            "basic auth was sent; check the username and password files in --secrets-dir"
+        } else {
+            "basic auth was sent; check --user/--password"
+        }
+    } else {
+        "no credentials were sent; set --auth, --auth-file, --user/--password, --bearer, \
+         --auth-command or --secrets-dir"
+    }
+}
+
+/// Runs the `probe` checks in order, returning CHECK/STATUS/DETAIL rows.
+/// Checks that depend on a failed one are reported as `skip`.
+fn probe(cli: &Cli, client: &Client, base: &Url) -> Result<Vec<Vec<String>>> {
+    let row = |check: &str, status: &str, detail: String| {
+        vec![check.to_string(), status.to_string(), detail]
+    };
+    let mut rows = Vec::new();
+
+    let reachable = if cli.tls_sni.is_some() {
+        rows.push(row("dns", "skip", "address pinned by --tls-sni".into()));
+        true
+    } else {
+        match resolve_base(base) {
+            Err(err) => {
+                rows.push(row(
+                    "dns",
+                    "FAIL",
+                    format!("{err:#}; check the host of the base URL"),
+                ));
+                rows.push(row("connect", "skip", "host did not resolve".into()));
+                false
+            }
+            Ok(addr) => {
+                rows.push(row("dns", "ok", addr.to_string()));
+                let timeout = cli.connect_timeout.unwrap_or(Duration::from_secs(5));
+                match TcpStream::connect_timeout(&addr, timeout) {
+                    Ok(_) => {
+                        rows.push(row("connect", "ok", format!("connected to {addr}")));
+                        true
+                    }
+                    Err(err) => {
+                        rows.push(row(
+                            "connect",
+                            "FAIL",
+                            format!("{err}; check the port, firewalls and network policies"),
+                        ));
+                        false
+                    }
+                }
+            }
+        }
+    };
+    if !reachable {
+        for check in ["ready", "auth", "query"] {
+            rows.push(row(check, "skip", "server not reachable".into()));
+        }
+        return Ok(rows);
+    }
+
+    let url = api_url(base, "-/ready");
+    rows.push(match send(cli, client.get(url)) {
+        Ok(response) if response.status().is_success() => row(
+            "ready",
+            "ok",
+            format!("HTTP {}", response.status().as_u16()),
+        ),
+        Ok(response) if response.status() == StatusCode::NOT_FOUND => row(
+            "ready",
+            "skip",
+            "/-/ready is not served (e.g. VictoriaMetrics)".into(),
+        ),
+        Ok(response) => row(
+            "ready",
+            "FAIL",
+            format!(
+                "HTTP {}; the server is up but not ready yet (starting or replaying its WAL)",
+                response.status().as_u16()
+            ),
+        ),
+        Err(err) => row("ready", "FAIL", format!("{err:#}")),
+    });
+
+    let url = api_url(base, "api/v1/query");
+    let started = Instant::now();
+    let response = match send_form(cli, client, url, &instant_params("vector(1)", None, None)) {
+        Ok(response) => response,
+        Err(err) => {
+            rows.push(row("auth", "skip", "query request failed".into()));
+            rows.push(row("query", "FAIL", format!("{err:#}")));
+            return Ok(rows);
+        }
+    };
+    let status = response.status();
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        rows.push(row(
+            "auth",
+            "FAIL",
+            format!("HTTP {}; {}", status.as_u16(), auth_hint(cli)),
+        ));
+        rows.push(row("query", "skip", "credentials were rejected".into()));
+        return Ok(rows);
+    }
+    rows.push(match auth_scheme(cli) {
+        "none" => row("auth", "ok", "no credentials required".into()),
+        scheme => row("auth", "ok", format!("{scheme} credentials accepted")),
+    });
+    rows.push(match parse_response(cli, response, started) {
+        Ok(parsed) => match parsed
+            .data
+            .as_ref()
+            .and_then(|d| d.pointer("/result/0/value/1"))
+        {
+            Some(Value::String(value)) if value == "1" => row(
+                "query",
+                "ok",
+                format!("vector(1) answered in {}ms", started.elapsed().as_millis()),
+            ),
+            _ => row(
+                "query",
+                "FAIL",
+                "vector(1) did not return 1; is the base URL a Prometheus-compatible API?".into(),
+            ),
+        },
+        Err(err) => row("query", "FAIL", format!("{err:#}")),
+    });
+    Ok(rows)
+}
+
+/// Distinguishes connectivity failures from slow or failed queries.
+fn request_error(cli: &Cli, err: reqwest::Error) -> anyhow::Error {
+    if !err.is_connect() {
+        return anyhow::Error::new(err).context("request failed");
+    }
+    let host = err
+        .url()
+        .map(|url| url.origin().ascii_serialization())
+        .unwrap_or_default();
+    let message = match cli.connect_timeout {
+        Some(timeout) if err.is_timeout() => format!(
+            "could not connect to {host} within {}s (--connect-timeout); \
+             the server is unreachable, not slow to answer the query",
+            timeout.as_secs_f64()
+        ),
+        _ => format!("could not connect to {host}; check the base URL and network access"),
+    };
+    anyhow::Error::new(err).context(message)
+}
+
+fn duration_ms(elapsed: Duration) -> f64 {
+    (elapsed.as_secs_f64() * 1000.0 * 10.0).round() / 10.0
+}
+
+/// Writes a verbose log line to stderr, as `event key=value ...` text or as a
+/// JSON object with timestamp, level and event fields.
+fn log_event(cli: &Cli, event: &str, fields: &[(&str, Value)]) {
+    if !cli.verbose {
+        return;
+    }
+    match cli.log_format {
+        LogFormat::Text => {
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|(key, value)| match value {
+                    Value::String(s) => format!("{key}={s}"),
+                    other => format!("{key}={other}"),
+                })
+                .collect();
+            eprintln!("{event} {}", fields.join(" "));
+        }
+        LogFormat::Json => {
+            let mut line = serde_json::Map::new();
+            line.insert(
+                "timestamp".to_string(),
+                Utc::now()
+                    .to_rfc3339_opts(SecondsFormat::Millis, true)
+                    .into(),
+            );
+            line.insert("level".to_string(), "debug".into());
+            line.insert("event".to_string(), event.into());
+            for (key, value) in fields {
+                line.insert(key.to_string(), value.clone());
+            }
+            eprintln!("{}", Value::Object(line));
+        }
+    }
+}
+
+fn parse_response(cli: &Cli, response: Response, started: Instant) -> Result<ApiResponse> {
+    let status = response.status();
+    let url = strip_credentials(response.url().as_str());
+    let reading = Instant::now();
+    let text = response.text().context("failed to read response body")?;
+    record_phase(Phase::Body, reading);
+    write_transcript(
+        "response_body",
+        &[
+            ("url", url.into()),
+            ("bytes", text.len().into()),
+            ("body", text.as_str().into()),
+        ],
+    )?;
+    let meta = ResponseMeta {
+        status: status.as_u16(),
+        bytes: text.len(),
+        elapsed: started.elapsed(),
+    };
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        let preview = text.trim().chars().take(200).collect::<String>();
+        let hint = auth_hint(cli);
+        if preview.is_empty() {
+            bail!("authentication failed ({status}): {hint}");
+        }
+        bail!("authentication failed ({status}): {hint} (response: {preview})");
+    }
+
+    let parsing = Instant::now();
+    let mut parsed: ApiResponse = serde_json::from_str(&text).with_context(|| {
+        let preview = text.chars().take(200).collect::<String>();
+        format!("failed to parse response as JSON (status {status}): {preview}")
+    })?;
+    record_phase(Phase::Parse, parsing);
+
+    check_api_status(
+        cli,
+        status,
+        &parsed.status,
+        parsed.error_type.as_deref(),
+        parsed.error.as_deref(),
+    )?;
+    if let Some(warnings) = &parsed.warnings {
+        check_warnings(cli, warnings)?;
+    }
+
+    parsed.meta = meta;
+    Ok(parsed)
+}
+
+/// Fails on a non-`success` API status, unless `--expect-status any` accepts
+/// it on a 2xx response.
+fn check_api_status(
+    cli: &Cli,
+    status: StatusCode,
+    api_status: &str,
+    error_type: Option<&str>,
+    error: Option<&str>,
+) -> Result<()> {
+    if api_status == "success" {
+        return Ok(());
+    }
+    let error_type = error_type.unwrap_or("unknown");
+    let error = error.unwrap_or("unknown error");
+    // Some backends answer HTTP 200 with non-standard status strings.
+    if cli.expect_status == ExpectStatus::Any && status.is_success() {
+        eprintln!("warning: response status is {api_status:?} ({error_type}: {error})");
+        return Ok(());
+    }
+    Err(ApiError {
+        error_type: error_type.to_string(),
+        error: error.to_string(),
+        status: status.as_u16(),
+    }
+    .into())
+}
+
+/// Prints server warnings, failing on the first one matching --abort-on-warning.
+fn check_warnings(cli: &Cli, warnings: &[String]) -> Result<()> {
+    for warning in warnings {
+        eprintln!("warning: {warning}");
+    }
+    let fatal = warnings.iter().find(|warning| {
+        cli.abort_on_warning
+            .iter()
+            .any(|pattern| warning.contains(pattern.as_str()))
+    });
+    if let Some(warning) = fatal {
+        bail!("aborting on server warning: {warning}");
+    }
+    Ok(())
+}
+
+/// Fails unless the result type matches --expect-type.
+fn check_result_type(cli: &Cli, actual: Option<&str>) -> Result<()> {
+    if let Some(expected) = cli.expect_type.and_then(|t| t.to_possible_value()) {
+        let actual = actual.unwrap_or("none");
+        if actual != expected.get_name() {
+            bail!("expected a {} result but got {actual}", expected.get_name());
+        }
+    }
+    Ok(())
+}
+
+/// Applies the result transforms requested on the command line, in place.
+fn transform_data(cli: &Cli, data: &mut Value) {
+    redact(cli, data);
+    if !cli.rename_labels.is_empty() {
+        for_each_label_set(data, |labels| {
+            for (old, new) in &cli.rename_labels {
+                if let Some(value) = labels.remove(old) {
+                    labels.insert(new.clone(), value);
+                }
+            }
+        });
+    }
+    if let Some(regex) = &cli.out_labels_regex {
+        for_each_label_set(data, |labels| labels.retain(|name, _| regex.is_match(name)));
+    }
+    if let Some(points) = cli.downsample {
+        downsample(data, points as usize);
+    }
+}
+
+/// Applies the JSON-only sample reshaping flags: --time-unit ms,
+/// --sample-timestamp-as-column and --flatten, in that order.
+fn shape_samples(cli: &Cli, data: &mut Value) {
+    if cli.time_unit == EpochUnit::Ms {
+        sample_timestamps_to_ms(data);
+    }
+    if cli.sample_timestamp_as_column {
+        split_sample_columns(data);
+    }
+    if cli.flatten {
+        flatten_results(data);
+    }
+}
+
+/// Replaces the float-seconds timestamps of vector, matrix and scalar samples
+/// with integer milliseconds.
+fn sample_timestamps_to_ms(data: &mut Value) {
+    let to_ms = |sample: &mut Value| {
+        if let Some(ts) = sample.get_mut(0)
+            && let Some(secs) = ts.as_f64()
+        {
+            *ts = ((secs * 1000.0).round() as i64).into();
+        }
+    };
+    let result_type = data
+        .get("resultType")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    match (result_type.as_deref(), data.get_mut("result")) {
+        (Some("scalar" | "string"), Some(sample)) => to_ms(sample),
+        (Some("vector"), Some(Value::Array(items))) => {
+            items
+                .iter_mut()
+                .filter_map(|item| item.get_mut("value"))
+                .for_each(to_ms);
+        }
+        (Some("matrix"), Some(Value::Array(items))) => {
+            for values in items.iter_mut().filter_map(|item| item.get_mut("values")) {
+                if let Value::Array(samples) = values {
+                    samples.iter_mut().for_each(to_ms);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Rewrites vector/matrix `.result` elements so every sample becomes its own
+/// `{metric, timestamp, value}` object instead of a nested `[ts, value]` pair.
+fn split_sample_columns(data: &mut Value) {
+    let key = match data.get("resultType").and_then(Value::as_str) {
+        Some("vector") => "value",
+        Some("matrix") => "values",
+        _ => return,
+    };
+    let Some(Value::Array(items)) = data.get_mut("result") else {
+        return;
+    };
+    let sample = |metric: &Value, pair: &Value| {
+        serde_json::json!({
+            "metric": metric,
+            "timestamp": pair.get(0).cloned().unwrap_or(Value::Null),
+            "value": pair.get(1).cloned().unwrap_or(Value::Null),
+        })
+    };
+    *items = items
+        .iter()
+        .flat_map(|item| {
+            let metric = item.get("metric").cloned().unwrap_or(Value::Null);
+            let pairs = match item.get(key) {
+                Some(Value::Array(values)) if key == "values" => values.clone(),
+                Some(pair) => vec![pair.clone()],
+                None => Vec::new(),
+            };
+            pairs
+                .iter()
+                .map(|pair| sample(&metric, pair))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+}
+
+/// The elements `-o ndjson` prints one per line: `.result` entries (or the
+/// array itself with --result), or the whole payload when it is not a list.
+fn ndjson_items(payload: Value) -> Vec<Value> {
+    match payload {
+        Value::Array(items) => items,
+        Value::Object(mut map) => match map.remove("result") {
+            Some(Value::Array(items)) => items,
+            Some(result) => {
+                map.insert("result".to_string(), result);
+                vec![Value::Object(map)]
+            }
+            None => vec![Value::Object(map)],
+        },
+        other => vec![other],
+    }
+}
+
+/// Sends a range query and prints each series as an NDJSON line as soon as it
+/// has been parsed from the body, applying the usual per-series transforms.
+fn stream_ndjson(
+    cli: &Cli,
+    client: &Client,
+    url: Url,
+    params: &[(String, String)],
+    method: Method,
+) -> Result<()> {
+    let started = Instant::now();
+    let response = send_query_request(cli, client, url, params, method)?;
+    let status = response.status();
+    if !status.is_success() {
+        parse_response(cli, response, started)?;
+        bail!("unexpected HTTP status {status}");
+    }
+    let url = strip_credentials(response.url().as_str());
+    // The --transcript body is the one thing that has to be kept whole.
+    let mut body = TeeReader {
+        inner: response,
+        copy: cli.transcript.as_ref().map(|_| Vec::new()),
+    };
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let streamed = stream::for_each_result(&mut body, |item| {
+        let mut data = serde_json::json!({ "resultType": "matrix", "result": [item] });
+        transform_data(cli, &mut data);
+        shape_samples(cli, &mut data);
+        for item in ndjson_items(data) {
+            writeln!(stdout, "{}", serde_json::to_string(&item)?)?;
+        }
+        stdout.flush()?;
+        Ok(())
+    });
+    if let Some(copy) = &body.copy {
+        let text = String::from_utf8_lossy(copy);
+        write_transcript(
+            "response_body",
+            &[
+                ("url", url.into()),
+                ("bytes", copy.len().into()),
+                ("body", text.as_ref().into()),
+            ],
+        )?;
+    }
+    let envelope = streamed.context("failed to stream response")?;
+    check_api_status(
+        cli,
+        status,
+        &envelope.status,
+        envelope.error_type.as_deref(),
+        envelope.error.as_deref(),
+    )?;
+    check_warnings(cli, &envelope.warnings)?;
+    check_result_type(cli, envelope.result_type.as_deref())?;
+    if let Some(limit) = cli.series_limit_warn
+        && envelope.items as u64 > limit
+    {
+        eprintln!(
+            "warning: result has {} series, more than --series-limit-warn {limit}; the selector may be too broad",
+            envelope.items
+        );
+    }
+    log_event(
+        cli,
+        "stream",
+        &[
+            ("series", envelope.items.into()),
+            ("duration_ms", duration_ms(started.elapsed()).into()),
+        ],
+    );
+    Ok(())
+}
+
+/// Passes reads through, keeping a copy of the bytes when `copy` is set.
+struct TeeReader<R> {
+    inner: R,
+    copy: Option<Vec<u8>>,
+}
+
+impl<R: Read> Read for TeeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(copy) = &mut self.copy {
+            copy.extend_from_slice(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+/// Replaces each element of `.result` (or of a plain array, e.g. series) with
+/// a flat object keyed by dotted paths such as `metric.job` and `value.1`.
+fn flatten_results(data: &mut Value) {
+    let items = match data {
+        Value::Object(map) => match map.get_mut("result") {
+            Some(Value::Array(items)) => items,
+            _ => return,
+        },
+        Value::Array(items) => items,
+        _ => return,
+    };
+    for item in items.iter_mut().filter(|item| item.is_object()) {
+        let mut flat = serde_json::Map::new();
+        flatten_into("", item, &mut flat);
+        *item = Value::Object(flat);
+    }
+}
+
+fn flatten_into(prefix: &str, value: &Value, out: &mut serde_json::Map<String, Value>) {
+    let key = |name: &str| {
+        if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{prefix}.{name}")
+        }
+    };
+    match value {
+        Value::Object(map) => {
+            for (name, item) in map {
+                flatten_into(&key(name), item, out);
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                flatten_into(&key(&i.to_string()), item, out);
+            }
+        }
+        scalar => {
+            out.insert(prefix.to_string(), scalar.clone());
+        }
+    }
+}
+
+/// Downsamples every series of a matrix result in place.
+fn downsample(data: &mut Value, points: usize) {
+    if data.get("resultType").and_then(Value::as_str) != Some("matrix") {
+        return;
+    }
+    let Some(Value::Array(series)) = data.get_mut("result") else {
+        return;
+    };
+    for item in series {
+        if let Some(Value::Array(values)) = item.get_mut("values") {
+            *values = lttb(values, points);
+        }
+    }
+}
+
+/// Largest-triangle-three-buckets over `[ts, "value"]` samples: keeps the first
+/// and last sample and, from each bucket in between, the sample forming the
+/// largest triangle with the previously kept sample and the next bucket's mean.
+fn lttb(samples: &[Value], points: usize) -> Vec<Value> {
+    if points >= samples.len() || points < 3 {
+        return samples.to_vec();
+    }
+    let xy: Vec<(f64, f64)> = samples
+        .iter()
+        .map(|sample| {
+            let x = sample.get(0).and_then(Value::as_f64).unwrap_or(0.0);
+            let y = sample
+                .get(1)
+                .and_then(Value::as_str)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0.0);
+            (x, y)
+        })
+        .collect();
+
+    let every = (samples.len() - 2) as f64 / (points - 2) as f64;
+    let mut kept = vec![samples[0].clone()];
+    let mut a = 0;
+    for i in 0..points - 2 {
+        let next_start = ((i + 1) as f64 * every) as usize + 1;
+        let next_end = (((i + 2) as f64 * every) as usize + 1).min(samples.len());
+        let next = &xy[next_start..next_end];
+        let avg_x = next.iter().map(|p| p.0).sum::<f64>() / next.len() as f64;
+        let avg_y = next.iter().map(|p| p.1).sum::<f64>() / next.len() as f64;
+
+        let start = (i as f64 * every) as usize + 1;
+        let end = next_start;
+        let (ax, ay) = xy[a];
+        let mut best = start;
+        let mut best_area = -1.0;
+        for (j, &(x, y)) in xy.iter().enumerate().take(end).skip(start) {
+            let area = ((ax - avg_x) * (y - ay) - (ax - x) * (avg_y - ay)).abs();
+            if area > best_area {
+                best_area = area;
+                best = j;
+            }
+        }
+        kept.push(samples[best].clone());
+        a = best;
+    }
+    kept.push(samples[samples.len() - 1].clone());
+    kept
+}
+
+const REDACTED: &str = "REDACTED";
+
+/// Scrubs label values selected by `--redact`/`--redact-regex` from query
+/// results (`.result[].metric`), series label sets and plain value lists.
+fn redact(cli: &Cli, data: &mut Value) {
+    if cli.redact.is_empty() && cli.redact_regex.is_none() {
+        return;
+    }
+
+    for_each_label_set(data, |labels| redact_labels(cli, labels));
+    if let Value::Array(items) = data {
+        for item in items {
+            if let Value::String(value) = item
+                && redact_matches(cli, value)
+            {
+                *value = REDACTED.to_string();
+            }
+        }
+    }
+}
+
+/// Calls `f` on every label set: `.result[].metric` of query results, or each
+/// object of a series list.
+fn for_each_label_set(data: &mut Value, mut f: impl FnMut(&mut serde_json::Map<String, Value>)) {
+    match data {
+        Value::Object(map) => {
+            if let Some(Value::Array(results)) = map.get_mut("result") {
+                for result in results {
+                    if let Some(Value::Object(labels)) = result.get_mut("metric") {
+                        f(labels);
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                if let Value::Object(labels) = item {
+                    f(labels);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn redact_labels(cli: &Cli, labels: &mut serde_json::Map<String, Value>) {
+    for (name, value) in labels.iter_mut() {
+        if let Value::String(text) = value {
+            redact_value(cli, name, text);
+        }
+    }
+}
+
+/// Scrubs one value of label `name` if `--redact` or `--redact-regex` selects it.
+fn redact_value(cli: &Cli, name: &str, value: &mut String) {
+    if !value.is_empty()
+        && (cli.redact.iter().any(|label| label == name) || redact_matches(cli, value))
+    {
+        *value = REDACTED.to_string();
+    }
+}
+
+fn redact_matches(cli: &Cli, value: &str) -> bool {
+    cli.redact_regex
+        .as_ref()
+        .is_some_and(|regex| regex.is_match(value))
+}
+
+fn print_meta(cli: &Cli, meta: &ResponseMeta) {
+    if cli.meta {
+        eprintln!(
+            "meta: status={} bytes={} time={:.1}ms",
+            meta.status,
+            meta.bytes,
+            meta.elapsed.as_secs_f64() * 1000.0
+        );
+    }
+}
+
+fn output_data(
+    cli: &Cli,
+    mut response: ApiResponse,
+    params: &[(String, String)],
+    metadata: &MetadataMap,
+) -> Result<()> {
+    let formatting = Instant::now();
+    check_result_type(
+        cli,
+        response
+            .data
+            .as_ref()
+            .and_then(|data| data.get("resultType"))
+            .and_then(Value::as_str),
+    )?;
+    let meta = std::mem::take(&mut response.meta);
+    let staleness = match (cli.max_staleness, &response.data) {
+        (Some(max), Some(data)) => {
+            // Report series as they will be printed, e.g. with --redact applied.
+            let mut data = data.clone();
+            transform_data(cli, &mut data);
+            Some(check_staleness(&data, max))
+        }
+        _ => None,
+    };
+    let series = series_count(response.data.as_ref());
+    let values = check_values(cli, response.data.as_ref());
+    if let Some(order) = cli.sort_by_value
+        && let Some(data) = response.data.as_mut()
+    {
+        sort_by_value(data, order);
+    }
+    if let Some(limit) = cli.series_limit_warn
+        && let Some(data) = response.data.as_ref()
+        && matches!(
+            data.get("resultType").and_then(Value::as_str),
+            Some("vector" | "matrix")
+        )
+        && let Some(count) = series
+        && count as u64 > limit
+    {
+        eprintln!(
+            "warning: result has {count} series, more than --series-limit-warn {limit}; the selector may be too broad"
+        );
+    }
+    if let Some(max) = cli.max_series
+        && let Some(data) = response.data.as_mut()
+        && matches!(
+            data.get("resultType").and_then(Value::as_str),
+            Some("vector" | "matrix")
+        )
+        && let Some(Value::Array(results)) = data.get_mut("result")
+        && results.len() as u64 > max
+    {
+        if !cli.truncate {
+            bail!(
+                "result has {} series, more than --max-series {max}; narrow the selector or pass --truncate",
+                results.len()
+            );
+        }
+        eprintln!("warning: truncating {} series to {max}", results.len());
+        results.truncate(max as usize);
+    }
+    if let Some(head) = cli.head
+        && let Some(data) = response.data.as_mut()
+        && matches!(
+            data.get("resultType").and_then(Value::as_str),
+            Some("vector" | "matrix")
+        )
+        && let Some(Value::Array(results)) = data.get_mut("result")
+    {
+        results.truncate(head);
+    }
+    if let Some(token) = &cli.null_as
+        && is_empty_result(response.data.as_ref())
+    {
+        write_line(&mut io::stdout().lock(), token, !cli.no_newline)?;
+    } else if let Some(path) = &cli.template_file {
+        let mut data = response.data.unwrap_or(Value::Null);
+        transform_data(cli, &mut data);
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(render_template(path, &data)?.as_bytes())?;
+        stdout.flush()?;
+    } else if let Some(separator) = &cli.label_join {
+        let mut data = response.data.unwrap_or(Value::Null);
+        transform_data(cli, &mut data);
+        print_table(
+            &["LABELS", "VALUE"],
+            &joined_label_rows(cli, &data, separator)?,
+        )?;
+    } else if cli.group_by_name {
+        let mut data = response.data.unwrap_or(Value::Null);
+        transform_data(cli, &mut data);
+        print_grouped(cli, &data)?;
+    } else if cli.summary {
+        let mut data = response.data.unwrap_or(Value::Null);
+        transform_data(cli, &mut data);
+        print_table(&["STAT", "VALUE"], &summary_rows(cli, &data)?)?;
+    } else if cli.values_only {
+        let values = sample_values(response.data.as_ref().unwrap_or(&Value::Null))?
+            .into_iter()
+            .map(|value| match value {
+                Value::String(raw) => Value::String(format_value(cli, &raw)),
+                other => other,
+            })
+            .collect();
+        print_lines(&Value::Array(values), cli.page_size, !cli.no_newline)?;
+    } else if cli.latest_timestamp {
+        let mut data = response.data.unwrap_or(Value::Null);
+        transform_data(cli, &mut data);
+        print_table(
+            &["SERIES", "TIMESTAMP"],
+            &latest_timestamps(&data, cli.oldest_first)?,
+        )?;
+    } else if let Some(label) = &cli.pivot_by {
+        let mut data = response.data.unwrap_or(Value::Null);
+        transform_data(cli, &mut data);
+        let (headers, rows) = pivot_rows(cli, &data, label)?;
+        let headers: Vec<&str> = headers.iter().map(String::as_str).collect();
+        print_table(&headers, &rows)?;
+    } else if cli.output == OutputFormat::Openmetrics {
+        let mut data = response.data.unwrap_or(Value::Null);
+        transform_data(cli, &mut data);
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(render_openmetrics(&data, metadata)?.as_bytes())?;
+        stdout.flush()?;
+    } else if cli.output == OutputFormat::Markdown {
+        let mut data = response.data.unwrap_or(Value::Null);
+        transform_data(cli, &mut data);
+        let (headers, rows) = sample_rows(cli, &data)?;
+        let headers: Vec<&str> = headers.iter().map(String::as_str).collect();
+        print_markdown_table(&headers, &rows)?;
+    } else if cli.output == OutputFormat::Ndjson {
+        let payload = result_payload(cli, response)?;
+        let items = ndjson_items(payload);
+        let page_size = cli.page_size.map_or(items.len().max(1), |n| n as usize);
+        let mut stdout = BufWriter::new(io::stdout().lock());
+        for page in items.chunks(page_size) {
+            for item in page {
+                writeln!(stdout, "{}", serde_json::to_string(item)?)?;
+            }
+            stdout.flush()?;
+        }
+    } else if cli.output == OutputFormat::RemotewriteJson {
+        let mut data = response.data.unwrap_or(Value::Null);
+        transform_data(cli, &mut data);
+        emit_json(cli, &remote_write_series(&data)?)?;
+    } else {
+        let payload = result_payload(cli, response)?;
+        if cli.annotate {
+            emit_json(cli, &annotate(cli, params, payload))?;
+        } else {
+            emit_json(cli, &payload)?;
+        }
+    }
+    print_meta(cli, &meta);
+    if let Some(stale) = staleness
+        && stale > 0
+    {
+        bail!("found {stale} series older than --max-staleness");
+    }
+    record_phase(Phase::Output, formatting);
+    check_series_count(cli, series)?;
+    values
+}
+
+/// Sorts the series of a vector result by value. NaN (and unparsable) values
+/// go last in both orders, and equal values are ordered by label set so the
+/// output does not depend on the server's ordering.
+fn sort_by_value(data: &mut Value, order: SortOrder) {
+    if data.get("resultType").and_then(Value::as_str) != Some("vector") {
+        return;
+    }
+    let Some(Value::Array(items)) = data.get_mut("result") else {
+        return;
+    };
+    let value = |item: &Value| {
+        item.pointer("/value/1")
+            .and_then(Value::as_str)
+            .and_then(|raw| raw.parse::<f64>().ok())
+            .unwrap_or(f64::NAN)
+    };
+    items.sort_by(|a, b| {
+        let (x, y) = (value(a), value(b));
+        let by_value = match (x.is_nan(), y.is_nan()) {
+            (true, true) => std::cmp::Ordering::Equal,
+            (true, false) => std::cmp::Ordering::Greater,
+            (false, true) => std::cmp::Ordering::Less,
+            _ if order == SortOrder::Desc => y.total_cmp(&x),
+            _ => x.total_cmp(&y),
+        };
+        by_value.then_with(|| a["metric"].to_string().cmp(&b["metric"].to_string()))
+    });
+}
+
+/// Whether a query result has no data: missing, an empty vector/matrix, or a
+/// null/NaN scalar.
+fn is_empty_result(data: Option<&Value>) -> bool {
+    let Some(data) = data else {
+        return true;
+    };
+    match (
+        data.get("resultType").and_then(Value::as_str),
+        data.get("result"),
+    ) {
+        (_, None | Some(Value::Null)) => true,
+        (Some("scalar"), Some(sample)) => {
+            matches!(sample.get(1).and_then(Value::as_str), None | Some("NaN"))
+        }
+        (_, Some(Value::Array(items))) => items.is_empty(),
+        _ => false,
+    }
+}
+
+/// Number of series in a vector/matrix result or a series list.
+fn series_count(data: Option<&Value>) -> Option<usize> {
+    let data = data?;
+    data.get("result")
+        .unwrap_or(data)
+        .as_array()
+        .filter(|_| {
+            data.get("resultType")
+                .is_none_or(|t| t == "vector" || t == "matrix")
+        })
+        .map(Vec::len)
+}
+
+fn check_series_count(cli: &Cli, count: Option<usize>) -> Result<()> {
+    if cli.assert_series.is_none()
+        && cli.assert_series_min.is_none()
+        && cli.assert_series_max.is_none()
+    {
+        return Ok(());
+    }
+    let Some(count) = count else {
+        bail!("series assertions need a vector, matrix or series result");
+    };
+    if let Some(expected) = cli.assert_series
+        && count as u64 != expected
+    {
+        bail!("assertion failed: expected exactly {expected} series, got {count}");
+    }
+    if let Some(min) = cli.assert_series_min
+        && (count as u64) < min
+    {
+        bail!("assertion failed: expected at least {min} series, got {count}");
+    }
+    if let Some(max) = cli.assert_series_max
+        && count as u64 > max
+    {
+        bail!("assertion failed: expected at most {max} series, got {count}");
+    }
+    Ok(())
+}
+
+/// Checks `--expect-gt`/`--expect-lt` against every sample of a vector or
+/// scalar result.
+fn check_values(cli: &Cli, data: Option<&Value>) -> Result<()> {
+    if cli.expect_gt.is_none() && cli.expect_lt.is_none() {
+        return Ok(());
+    }
+    let values = sample_values(data.unwrap_or(&Value::Null))?;
+    if values.is_empty() {
+        bail!("assertion failed: result has no samples");
+    }
+    for value in values {
+        let raw = value.as_str().unwrap_or_default();
+        let number: f64 = raw
+            .parse()
+            .with_context(|| format!("sample value {raw:?} is not a number"))?;
+        if let Some(min) = cli.expect_gt
+            && (number.is_nan() || number <= min)
+        {
+            bail!("assertion failed: expected values > {min}, got {raw}");
+        }
+        if let Some(max) = cli.expect_lt
+            && (number.is_nan() || number >= max)
+        {
+            bail!("assertion failed: expected values < {max}, got {raw}");
+        }
+    }
+    Ok(())
+}
+
+/// Runs `attempt` every `interval` until its result passes the series and
+/// value assertions, failing once `max_wait` has elapsed.
+fn wait_for_assertions(
+    cli: &Cli,
+    interval: Duration,
+    max_wait: Option<Duration>,
+    mut attempt: impl FnMut() -> Result<ApiResponse>,
+) -> Result<ApiResponse> {
+    if cli.assert_series.is_none()
+        && cli.assert_series_min.is_none()
+        && cli.assert_series_max.is_none()
+        && cli.expect_gt.is_none()
+        && cli.expect_lt.is_none()
+    {
+        bail!("--repeat-until needs an assertion such as --expect-gt or --assert-series");
+    }
+    let started = Instant::now();
+    loop {
+        let outcome = attempt().and_then(|response| {
+            check_series_count(cli, series_count(response.data.as_ref()))?;
+            check_values(cli, response.data.as_ref())?;
+            Ok(response)
+        });
+        let err = match outcome {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+        if let Some(max_wait) = max_wait
+            && started.elapsed() + interval > max_wait
+        {
+            return Err(err.context(format!(
+                "condition not met within --max-wait {}s",
+                max_wait.as_secs_f64()
+            )));
+        }
+        eprintln!("waiting: {err:#}");
+        std::thread::sleep(interval);
+    }
+}
+
+/// Prints the age (now minus `value[0]`) of each vector sample to stderr,
+/// flagging those older than `max`. Returns the number of stale series.
+fn check_staleness(data: &Value, max: Duration) -> usize {
+    let now = Utc::now().timestamp_millis() as f64 / 1000.0;
+    let samples = data.get("result").and_then(Value::as_array);
+    let mut stale = 0;
+    for sample in samples.into_iter().flatten() {
+        let Some(timestamp) = sample
+            .get("value")
+            .and_then(|v| v.get(0))
+            .and_then(Value::as_f64)
+        else {
+            continue;
+        };
+        let age = (now - timestamp).max(0.0);
+        let series = format_series(sample.get("metric").unwrap_or(&Value::Null));
+        if age > max.as_secs_f64() {
+            stale += 1;
+            eprintln!("stale: {series} last sample {age:.0}s ago");
+        } else {
+            eprintln!("fresh: {series} last sample {age:.0}s ago");
+        }
+    }
+    stale
+}
+
+/// Wraps `data` with the query, evaluation time(s) and base URL (credentials
+/// removed) so archived output is self-describing.
+fn annotate(cli: &Cli, params: &[(String, String)], data: Value) -> Value {
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| Value::from(value.as_str()))
+    };
+    let matches: Vec<&str> = params
+        .iter()
+        .filter(|(key, _)| key == "match[]")
+        .map(|(_, value)| value.as_str())
+        .collect();
+
+    let mut wrapper = serde_json::Map::new();
+    match param("query") {
+        Some(query) => wrapper.insert("query".to_string(), query),
+        None => wrapper.insert("query".to_string(), matches.into()),
+    };
+    for key in ["start", "end", "step"] {
+        if let Some(value) = param(key) {
+            wrapper.insert(key.to_string(), value);
+        }
+    }
+    let time = param("time")
+        .unwrap_or_else(|| Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true).into());
+    wrapper.insert("time".to_string(), time);
+    wrapper.insert("base_url".to_string(), redacted_base_url(cli).into());
+    wrapper.insert("data".to_string(), data);
+    Value::Object(wrapper)
+}
+
+fn redacted_base_url(cli: &Cli) -> String {
+    let base = base_url(cli).unwrap_or_default();
+    match normalize_base(cli, &base) {
+        Ok(mut url) => {
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            url.to_string()
+        }
+        Err(_) => base,
+    }
+}
+
+/// Type and help text of a metric family, from /api/v1/metadata.
+struct MetricMetadata {
+    kind: String,
+    help: String,
+}
+
+type MetadataMap = HashMap<String, MetricMetadata>;
+
+/// Suffixes OpenMetrics appends to a family name for its samples.
+const FAMILY_SUFFIXES: [&str; 6] = ["_total", "_bucket", "_count", "_sum", "_created", "_info"];
+
+/// Fetches metadata for the metrics in a vector/matrix response when
+/// `-o openmetrics --fetch-metadata` needs it; empty otherwise.
+fn result_metadata(
+    cli: &Cli,
+    client: &Client,
+    base: &Url,
+    response: &ApiResponse,
+) -> Result<MetadataMap> {
+    if cli.output != OutputFormat::Openmetrics || !cli.fetch_metadata {
+        return Ok(HashMap::new());
+    }
+    let mut names = BTreeSet::new();
+    let results = response.data.as_ref().and_then(|data| data.get("result"));
+    for item in results.and_then(Value::as_array).into_iter().flatten() {
+        let Some(name) = item.pointer("/metric/__name__").and_then(Value::as_str) else {
+            continue;
+        };
+        names.insert(name.to_string());
+        if let Some(family) = FAMILY_SUFFIXES
+            .iter()
+            .find_map(|suffix| name.strip_suffix(suffix))
+        {
+            names.insert(family.to_string());
+        }
+    }
+    fetch_metadata(cli, client, base, names)
+}
+
+fn fetch_metadata(
+    cli: &Cli,
+    client: &Client,
+    base: &Url,
+    names: impl IntoIterator<Item = String>,
+) -> Result<MetadataMap> {
+    let url = api_url(base, "api/v1/metadata");
+    let mut metadata = HashMap::new();
+    for name in names {
+        let params = vec![("metric".to_string(), name.clone())];
+        let response = get_query(cli, client, url.clone(), params)?;
+        if let Some(entry) = response
+            .data
+            .as_ref()
+            .and_then(|data| data.get(&name)?.get(0))
+        {
+            metadata.insert(name, metric_metadata(entry));
+        }
+    }
+    Ok(metadata)
+}
+
+/// Fetches metadata for every metric the server knows about in one request.
+fn fetch_all_metadata(cli: &Cli, client: &Client, base: &Url) -> Result<MetadataMap> {
+    let url = api_url(base, "api/v1/metadata");
+    let response = get_query(cli, client, url, Vec::new())?;
+    let Some(families) = response.data.as_ref().and_then(Value::as_object) else {
+        return Ok(HashMap::new());
+    };
+    Ok(families
+        .iter()
+        .filter_map(|(name, entries)| Some((name.clone(), metric_metadata(entries.get(0)?))))
+        .collect())
+}
+
+fn metric_metadata(entry: &Value) -> MetricMetadata {
+    let field = |key: &str| {
+        entry
+            .get(key)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    MetricMetadata {
+        kind: field("type"),
+        help: field("help"),
+    }
+}
+
+/// Renders a vector or matrix result in the OpenMetrics text format, grouping
+/// samples into families with `# TYPE`/`# HELP` lines where metadata is known.
+fn render_openmetrics(data: &Value, metadata: &MetadataMap) -> Result<String> {
+    let matrix = match data.get("resultType").and_then(Value::as_str) {
+        Some("vector") => false,
+        Some("matrix") => true,
+        other => bail!(
+            "-o openmetrics supports vector and matrix results, got {}",
+            other.unwrap_or("unknown")
+        ),
+    };
+    let mut families: BTreeMap<String, (Option<&MetricMetadata>, Vec<String>)> = BTreeMap::new();
+    for item in data
+        .get("result")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let metric = item.get("metric").unwrap_or(&Value::Null);
+        let Some(name) = metric.get("__name__").and_then(Value::as_str) else {
+            bail!(
+                "-o openmetrics needs a metric name on every series; {} has none",
+                format_series(metric)
+            );
+        };
+        let (family, meta) = metric_family(name, metadata);
+        let labels = format_series(metric);
+        let labels = labels.strip_prefix(name).unwrap_or(&labels);
+        let labels = if labels == "{}" { "" } else { labels };
+        let samples: Vec<&Value> = if matrix {
+            item.get("values")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .collect()
+        } else {
+            item.get("value").into_iter().collect()
+        };
+        let lines = &mut families.entry(family).or_insert((meta, Vec::new())).1;
+        for sample in samples {
+            let (Some(timestamp), Some(value)) = (
+                sample.get(0).and_then(Value::as_f64),
+                sample.get(1).and_then(Value::as_str),
+            ) else {
+                continue;
+            };
+            lines.push(format!("{name}{labels} {value} {timestamp}"));
+        }
+    }
+
+    let mut out = String::new();
+    for (family, (meta, lines)) in families {
+        if let Some(meta) = meta {
+            out.push_str(&format!("# TYPE {family} {}\n", meta.kind));
+            if !meta.help.is_empty() {
+                let help = meta.help.replace('\\', "\\\\").replace('\n', "\\n");
+                out.push_str(&format!("# HELP {family} {help}\n"));
+            }
+        }
+        for line in lines {
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+    out.push_str("# EOF\n");
+    Ok(out)
+}
+
+/// The OpenMetrics family a sample name belongs to, and its metadata: the name
+/// itself (minus `_total` for counters), or the name without a family suffix.
+fn metric_family<'a>(
+    name: &str,
+    metadata: &'a MetadataMap,
+) -> (String, Option<&'a MetricMetadata>) {
+    if let Some(meta) = metadata.get(name) {
+        let family = match name.strip_suffix("_total") {
+            Some(family) if meta.kind == "counter" => family,
+            _ => name,
+        };
+        return (family.to_string(), Some(meta));
+    }
+    for suffix in FAMILY_SUFFIXES {
+        if let Some(family) = name.strip_suffix(suffix)
+            && let Some(meta) = metadata.get(family)
+        {
+            return (family.to_string(), Some(meta));
+        }
+    }
+    (name.to_string(), None)
+}
+
+/// One `catalog` row per metric name: the name with the type and help text
+/// of its family, or `unknown` when the server has no metadata for it.
+fn catalog_rows(
+    names: Option<&Value>,
+    regex: Option<&Regex>,
+    metadata: &MetadataMap,
+) -> Vec<Vec<String>> {
+    names
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .filter(|name| regex.is_none_or(|regex| regex.is_match(name)))
+        .map(|name| match metric_family(name, metadata).1 {
+            Some(meta) => vec![name.to_string(), meta.kind.clone(), meta.help.clone()],
+            None => vec![name.to_string(), "unknown".to_string(), String::new()],
+        })
+        .collect()
+}
+
+/// Reshapes a vector or matrix result into remote-write style timeseries with
+/// millisecond timestamps and numeric values.
+fn remote_write_series(data: &Value) -> Result<Value> {
+    let key = match data.get("resultType").and_then(Value::as_str) {
+        Some("vector") => "value",
+        Some("matrix") => "values",
+        other => bail!(
+            "remotewrite-json output supports vector and matrix results, got {}",
+            other.unwrap_or("unknown")
+        ),
+    };
+    let series = data.get("result").and_then(Value::as_array);
+    let timeseries = series
+        .into_iter()
+        .flatten()
+        .map(|item| {
+            let samples: Vec<&Value> = match item.get(key) {
+                Some(Value::Array(values)) if key == "values" => values.iter().collect(),
+                Some(value) => vec![value],
+                None => Vec::new(),
+            };
+            let samples: Vec<Value> = samples
+                .into_iter()
+                .filter_map(|sample| {
+                    let timestamp = sample.get(0)?.as_f64()?;
+                    let raw = sample.get(1)?.as_str()?;
+                    let value = match raw.parse::<f64>() {
+                        Ok(value) if value.is_finite() => Value::from(value),
+                        _ => Value::from(raw),
+                    };
+                    Some(serde_json::json!({
+                        "value": value,
+                        "timestamp": (timestamp * 1000.0).round() as i64,
+                    }))
+                })
+                .collect();
+            serde_json::json!({
+                "labels": item.get("metric").cloned().unwrap_or_else(|| serde_json::json!({})),
+                "samples": samples,
+            })
+        })
+        .collect();
+    Ok(Value::Array(timeseries))
+}
+
+/// Extracts `value[1]` from each vector element, or the single value of a
+/// scalar/string result.
+fn sample_values(data: &Value) -> Result<Vec<Value>> {
+    let result = data.get("result").unwrap_or(&Value::Null);
+    match data.get("resultType").and_then(Value::as_str) {
+        Some("vector") => Ok(result
+            .as_array()
+            .map(|items| {
+                items
+                    .iter()
+                    .filter_map(|item| item.get("value").and_then(|v| v.get(1)).cloned())
+                    .collect()
+            })
+            .unwrap_or_default()),
+        Some("scalar" | "string") => Ok(result.get(1).cloned().into_iter().collect()),
+        other => bail!(
+            "--values-only supports vector and scalar results, got {}",
+            other.unwrap_or("unknown")
+        ),
+    }
+}
+
+/// Rolls the values of a vector result up into `--summary` rows. NaN values
+/// are skipped; percentiles interpolate linearly between the closest ranks.
+fn summary_rows(cli: &Cli, data: &Value) -> Result<Vec<Vec<String>>> {
+    if data.get("resultType").and_then(Value::as_str) != Some("vector") {
+        bail!("--summary expects a vector result");
+    }
+    let mut values: Vec<f64> = sample_values(data)?
+        .iter()
+        .filter_map(Value::as_str)
+        .filter_map(|raw| raw.parse::<f64>().ok())
+        .filter(|value| !value.is_nan())
+        .collect();
+    values.sort_by(f64::total_cmp);
+    let mut rows = vec![vec!["count".to_string(), values.len().to_string()]];
+    if values.is_empty() {
+        return Ok(rows);
+    }
+    let percentile = |p: f64| {
+        let rank = p * (values.len() - 1) as f64;
+        let (low, high) = (values[rank.floor() as usize], values[rank.ceil() as usize]);
+        low + (high - low) * rank.fract()
+    };
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let stats = [
+        ("min", values[0]),
+        ("max", values[values.len() - 1]),
+        ("mean", mean),
+        ("p50", percentile(0.5)),
+        ("p90", percentile(0.9)),
+        ("p99", percentile(0.99)),
+    ];
+    rows.extend(
+        stats
+            .into_iter()
+            .map(|(name, value)| vec![name.to_string(), format_value(cli, &value.to_string())]),
+    );
+    Ok(rows)
+}
+
+fn result_payload(cli: &Cli, response: ApiResponse) -> Result<Value> {
+    let mut data = response.data.unwrap_or(Value::Null);
+    transform_data(cli, &mut data);
+    shape_samples(cli, &mut data);
+    if cli.result {
+        match data.get_mut("result") {
+            Some(result) if !result.is_null() => Ok(result.take()),
+            _ => bail!("response had no .data.result"),
+        }
+    } else {
+        Ok(data)
+    }
+}
+
+/// Prints a list endpoint's values. `label` names the label they are values
+/// of, so `--redact LABEL` scrubs every item.
+fn output_list(cli: &Cli, label: Option<&str>, response: ApiResponse) -> Result<()> {
+    let formatting = Instant::now();
+    let mut data = response.data.unwrap_or(Value::Null);
+    redact(cli, &mut data);
+    if let Some(label) = label
+        && let Value::Array(items) = &mut data
+    {
+        for item in items {
+            if let Value::String(value) = item {
+                redact_value(cli, label, value);
+            }
+        }
+    }
+    if cli.as_regex {
+        let alternatives: Vec<String> = data
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .map(regex::escape)
+            .collect();
+        if alternatives.is_empty() {
+            // `()` matches the empty string, i.e. every series without the label.
+            bail!("--as-regex: the list is empty, so there is no pattern to print");
+        }
+        let line = format!("({})", alternatives.join("|"));
+        write_line(&mut io::stdout().lock(), &line, !cli.no_newline)?;
+    } else if cli.lines {
+        print_lines(&data, cli.page_size, !cli.no_newline)?;
+    } else {
+        emit_json(cli, &data)?;
+    }
+    record_phase(Phase::Output, formatting);
+    print_meta(cli, &response.meta);
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum JsonStyle {
+    Compact,
+    Pretty,
+    CompactArrays,
+}
+
+fn json_style(cli: &Cli) -> JsonStyle {
+    if cli.json_compact_arrays {
+        JsonStyle::CompactArrays
+    } else if cli.pretty {
+        JsonStyle::Pretty
+    } else {
+        JsonStyle::Compact
+    }
+}
+
+fn emit_json(cli: &Cli, value: &Value) -> Result<()> {
+    let style = json_style(cli);
+    let newline = !cli.no_newline;
+    let Some(page_size) = cli.page_size else {
+        return print_json(value, style, newline);
+    };
+    match value {
+        Value::Array(items) => print_pages(items, page_size, style, newline, |page| {
+            Value::Array(page.to_vec())
+        }),
+        Value::Object(map) if map.get("result").is_some_and(Value::is_array) => {
+            let mut envelope = map.clone();
+            let Some(Value::Array(items)) = envelope.remove("result") else {
+                unreachable!("checked above");
+            };
+            print_pages(&items, page_size, style, newline, |page| {
+                let mut envelope = envelope.clone();
+                envelope.insert("result".to_string(), Value::Array(page.to_vec()));
+                Value::Object(envelope)
+            })
+        }
+        _ => print_json(value, style, newline),
+    }
+}
+
+/// Prints each chunk of `items`, wrapped by `wrap` (the bare array, or the
+/// `{resultType,result}` object around it), as its own JSON document, so
+/// consumers can start processing before the whole result has been serialized.
+fn print_pages(
+    items: &[Value],
+    page_size: u64,
+    style: JsonStyle,
+    newline: bool,
+    wrap: impl Fn(&[Value]) -> Value,
+) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    let mut pages: Vec<&[Value]> = items.chunks(page_size as usize).collect();
+    if pages.is_empty() {
+        // An empty result is still one (empty) document, not no output at all.
+        pages.push(&[]);
+    }
+    let count = pages.len();
+    for (i, page) in pages.into_iter().enumerate() {
+        let output = render_json(&wrap(page), style)?;
+        write_line(&mut stdout, &output, newline || i + 1 < count)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+fn print_json(value: &Value, style: JsonStyle, newline: bool) -> Result<()> {
+    let output = render_json(value, style)?;
+    let mut stdout = io::stdout().lock();
+    write_line(&mut stdout, &output, newline)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+fn write_line(out: &mut impl Write, line: &str, newline: bool) -> Result<()> {
+    if newline {
+        writeln!(out, "{line}")?;
+    } else {
+        write!(out, "{line}")?;
+    }
+    Ok(())
+}
+
+fn render_json(value: &Value, style: JsonStyle) -> Result<String> {
+    Ok(match style {
+        JsonStyle::Compact => serde_json::to_string(value)?,
+        JsonStyle::Pretty => serde_json::to_string_pretty(value)?,
+        JsonStyle::CompactArrays => {
+            let mut out = String::new();
+            write_compact(value, 0, &mut out)?;
+            out
+        }
+    })
+}
+
+/// Pretty-prints `value` with two-space indentation, except that arrays holding
+/// only scalars (such as `[ts, "value"]` sample pairs) stay on a single line.
+fn write_compact(value: &Value, indent: usize, out: &mut String) -> Result<()> {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            out.push_str("{\n");
+            for (i, (key, item)) in map.iter().enumerate() {
+                out.push_str(&"  ".repeat(indent + 1));
+                out.push_str(&serde_json::to_string(key)?);
+                out.push_str(": ");
+                write_compact(item, indent + 1, out)?;
+                if i + 1 < map.len() {
+                    out.push(',');
+                }
+                out.push('\n');
+            }
+            out.push_str(&"  ".repeat(indent));
+            out.push('}');
+        }
+        Value::Array(items) => write_compact_array(items, indent, out)?,
+        _ => out.push_str(&serde_json::to_string(value)?),
+    }
+    Ok(())
+}
+
+fn write_compact_array(items: &[Value], indent: usize, out: &mut String) -> Result<()> {
+    let is_leaf = items
+        .iter()
+        .all(|item| !matches!(item, Value::Array(_) | Value::Object(_)));
+    if is_leaf {
+        let parts = items
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        out.push('[');
+        out.push_str(&parts.join(", "));
+        out.push(']');
+        return Ok(());
+    }
+
+    out.push_str("[\n");
+    for (i, item) in items.iter().enumerate() {
+        out.push_str(&"  ".repeat(indent + 1));
+        write_compact(item, indent + 1, out)?;
+        if i + 1 < items.len() {
+            out.push(',');
+        }
+        out.push('\n');
+    }
+    out.push_str(&"  ".repeat(indent));
+    out.push(']');
+    Ok(())
+}
+
+/// Prints `timestamp series value` for every vector sample whose value differs
+/// from the last one seen for the same label set. Changes are tracked by the
+/// raw label set; only the printed series has --redact and the label
+/// transforms applied, since those can make distinct series look the same.
+fn print_changes(
+    cli: &Cli,
+    data: &Value,
+    last_seen: &mut HashMap<String, Value>,
+    out: &mut impl Write,
+) -> Result<()> {
+    let mut shown = data.clone();
+    transform_data(cli, &mut shown);
+    let samples = data.get("result").and_then(Value::as_array);
+    let shown = shown.get("result").and_then(Value::as_array);
+    for (sample, shown) in samples
+        .into_iter()
+        .flatten()
+        .zip(shown.into_iter().flatten())
+    {
+        let Some(value) = sample.get("value").and_then(|v| v.get(1)) else {
+            continue;
+        };
+        let key = sample.get("metric").unwrap_or(&Value::Null).to_string();
+        if last_seen.get(&key) == Some(value) {
+            continue;
+        }
+        let timestamp = sample
+            .get("value")
+            .and_then(|v| v.get(0))
+            .and_then(Value::as_f64)
+            .map(format_timestamp)
+            .unwrap_or_default();
+        let value_text = value
+            .as_str()
+            .map_or_else(|| value.to_string(), |raw| format_value(cli, raw));
+        let metric = shown.get("metric").unwrap_or(&Value::Null);
+        writeln!(out, "{timestamp} {} {value_text}", format_series(metric))?;
+        last_seen.insert(key, value.clone());
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Renders `path` with `data`, `result` (the `.data.result` array) and
+/// `result_type` in scope, plus a `series` filter formatting label sets.
+fn render_template(path: &str, data: &Value) -> Result<String> {
+    let source = std::fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?;
+    let mut env = minijinja::Environment::new();
+    env.set_keep_trailing_newline(true);
+    env.add_filter("series", |metric: minijinja::Value| {
+        format_series(&serde_json::to_value(&metric).unwrap_or_default())
+    });
+    env.add_template("output", &source)
+        .with_context(|| format!("invalid template {path}"))?;
+    let context = minijinja::context! {
+        data => data,
+        result => data.get("result").unwrap_or(&Value::Null),
+        result_type => data.get("resultType").unwrap_or(&Value::Null),
+    };
+    env.get_template("output")?
+        .render(context)
+        .with_context(|| format!("failed to render {path}"))
+}
+
+/// `[labels, value]` rows for a vector or matrix result (latest sample), the
+/// labels rendered as `name=value` pairs joined by `separator`.
+fn joined_label_rows(cli: &Cli, data: &Value, separator: &str) -> Result<Vec<Vec<String>>> {
+    let matrix = match data.get("resultType").and_then(Value::as_str) {
+        Some("vector") => false,
+        Some("matrix") => true,
+        other => bail!(
+            "--label-join supports vector and matrix results, got {}",
+            other.unwrap_or("unknown")
+        ),
+    };
+    let results = data.get("result").and_then(Value::as_array);
+    Ok(results
+        .into_iter()
+        .flatten()
+        .map(|item| {
+            let labels = join_labels(item.get("metric").unwrap_or(&Value::Null), separator);
+            let sample = if matrix {
+                item.get("values")
+                    .and_then(Value::as_array)
+                    .and_then(|v| v.last())
+            } else {
+                item.get("value")
+            };
+            let value = sample
+                .and_then(|sample| sample.get(1))
+                .and_then(Value::as_str)
+                .map(|raw| format_value(cli, raw))
+                .unwrap_or_default();
+            vec![labels, value]
+        })
+        .collect())
+}
+
+/// Collapses a label map into `name=value` pairs joined by `separator`.
+fn join_labels(metric: &Value, separator: &str) -> String {
+    metric
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(name, value)| format!("{name}={}", value.as_str().unwrap_or_default()))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Prints a vector result as one section per `__name__`: a header line, then
+/// each series' remaining labels and value (only the value with --values-only).
+fn print_grouped(cli: &Cli, data: &Value) -> Result<()> {
+    if data.get("resultType").and_then(Value::as_str) != Some("vector") {
+        bail!("--group-by-name expects a vector result");
+    }
+    let mut groups: BTreeMap<&str, Vec<(String, String)>> = BTreeMap::new();
+    for item in data
+        .get("result")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let metric = item.get("metric").unwrap_or(&Value::Null);
+        let name = metric
+            .get("__name__")
+            .and_then(Value::as_str)
+            .unwrap_or("(no name)");
+        let value = item
+            .get("value")
+            .and_then(|v| v.get(1))
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let labels = format_series(metric);
+        let labels = labels.strip_prefix(name).unwrap_or(&labels).to_string();
+        groups
+            .entry(name)
+            .or_default()
+            .push((labels, format_value(cli, value)));
+    }
+
+    let width = groups
+        .values()
+        .flatten()
+        .map(|(labels, _)| labels.chars().count())
+        .max()
+        .unwrap_or(0);
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    for (name, series) in groups {
+        writeln!(stdout, "{name}")?;
+        for (labels, value) in series {
+            if cli.values_only {
+                writeln!(stdout, "  {value}")?;
+            } else {
+                writeln!(stdout, "  {labels:<width$}  {value}")?;
+            }
+        }
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Canonical text form of an instant query result for `hash`: one
+/// `labels value` line per series, sorted, without sample timestamps so
+/// re-evaluating an unchanged result yields the same text. Values are rounded
+/// per --float-precision.
+fn result_fingerprint(cli: &Cli, data: &Value) -> Result<String> {
+    let value = |raw: Option<&Value>| {
+        let raw = raw.and_then(Value::as_str).unwrap_or_default();
+        match (raw.parse::<f64>(), cli.float_precision) {
+            (Ok(value), Some(precision)) if value.is_finite() => format!("{value:.precision$}"),
+            _ => raw.to_string(),
+        }
+    };
+    let result = data.get("result").unwrap_or(&Value::Null);
+    let mut lines = match data.get("resultType").and_then(Value::as_str) {
+        Some("vector") => result
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|item| {
+                let labels = item.get("metric").cloned().unwrap_or_default();
+                // Object keys serialize sorted, so equal label sets match.
+                format!("{labels} {}", value(item.pointer("/value/1")))
+            })
+            .collect(),
+        Some("scalar" | "string") => vec![value(result.get(1))],
+        other => bail!(
+            "hash expects a vector, scalar or string result, got {}",
+            other.unwrap_or("none")
+        ),
+    };
+    lines.sort();
+    Ok(lines.join("\n"))
+}
+
+/// Formats a raw sample value for text output, applying --humanize.
+fn format_value(cli: &Cli, raw: &str) -> String {
+    match raw.parse::<f64>() {
+        Ok(value) if cli.humanize && value.is_finite() => {
+            humanize_value(value, cli.unit, cli.float_precision)
+        }
+        Ok(value)
+            if value.is_finite()
+                && let Some(precision) = cli.float_precision =>
+        {
+            format!("{value:.precision$}")
+        }
+        _ => raw.to_string(),
+    }
+}
+
+/// Scales `value` to a short human-readable form: SI suffixes for counts
+/// (`1.5M`), IEC suffixes for bytes (`2.0Gi`) and time units for seconds (`340ms`).
+/// `precision` overrides the default of one decimal place.
+fn humanize_value(value: f64, unit: Unit, precision: Option<usize>) -> String {
+    let (scales, base): (&[(f64, &str)], &str) = match unit {
+        Unit::Count => (
+            &[(1e15, "P"), (1e12, "T"), (1e9, "G"), (1e6, "M"), (1e3, "k")],
+            "",
+        ),
+        Unit::Bytes => (
+            &[
+                (1125899906842624.0, "Pi"),
+                (1099511627776.0, "Ti"),
+                (1073741824.0, "Gi"),
+                (1048576.0, "Mi"),
+                (1024.0, "Ki"),
+            ],
+            "B",
+        ),
+        Unit::Seconds => (
+            &[(86400.0, "d"), (3600.0, "h"), (60.0, "m"), (1.0, "s")],
+            "s",
+        ),
+    };
+
+    let magnitude = value.abs();
+    let (scaled, suffix) = scales
+        .iter()
+        .find(|(scale, _)| magnitude >= *scale)
+        .map(|(scale, suffix)| (value / scale, *suffix))
+        .unwrap_or_else(|| match unit {
+            Unit::Seconds if magnitude >= 1e-3 => (value * 1e3, "ms"),
+            Unit::Seconds if magnitude >= 1e-6 => (value * 1e6, "µs"),
+            Unit::Seconds if magnitude > 0.0 => (value * 1e9, "ns"),
+            _ => (value, base),
+        });
+    if let Some(precision) = precision {
+        format!("{scaled:.precision$}{suffix}")
+    } else if scaled.abs() >= 100.0 || scaled.fract() == 0.0 && suffix == base {
+        format!("{scaled:.0}{suffix}")
+    } else {
+        format!("{scaled:.1}{suffix}")
     }
+}
 
-    Ok(request)
+/// Renders a label set as a PromQL series selector, e.g. `up{job="api"}`.
+fn format_series(metric: &Value) -> String {
+    let Some(labels) = metric.as_object() else {
+        return "{}".to_string();
+    };
+    let name = labels
+        .get("__name__")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let pairs: Vec<String> = labels
+        .iter()
+        .filter(|(key, _)| key.as_str() != "__name__")
+        .map(|(key, value)| {
+            let value = value.as_str().unwrap_or_default();
+            format!("{key}=\"{}\"", escape_label_value(value))
+        })
+        .collect();
+    format!("{name}{{{}}}", pairs.join(","))
 }
 
-fn split_auth(auth: &str) -> Result<(String, String)> {
-    let mut parts = auth.splitn(2, ':');
-    let user = parts.next().unwrap_or_default();
-    let pass = parts.next();
-    if user.is_empty() || pass.is_none() {
-        bail!("auth must be in the form user:password");
+fn format_timestamp(secs: f64) -> String {
+    DateTime::from_timestamp_millis((secs * 1000.0).round() as i64)
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+        .unwrap_or_else(|| secs.to_string())
+}
+
+/// Maps each series of a vector result, keyed by its raw `name{labels}`, to
+/// the series as displayed (after --redact and the label transforms) and its
+/// raw value. Joining on the raw labels keeps series apart that the
+/// transforms would make look the same.
+fn vector_samples(cli: &Cli, data: &Value) -> Result<BTreeMap<String, (String, String)>> {
+    if data.get("resultType").and_then(Value::as_str) != Some("vector") {
+        bail!("compare expects a vector result");
     }
-    Ok((user.to_string(), pass.unwrap().to_string()))
+    let mut shown = data.clone();
+    transform_data(cli, &mut shown);
+    let raw = data.get("result").and_then(Value::as_array);
+    let shown = shown.get("result").and_then(Value::as_array);
+    Ok(raw
+        .into_iter()
+        .flatten()
+        .zip(shown.into_iter().flatten())
+        .filter_map(|(item, shown)| {
+            let value = item.get("value")?.get(1)?.as_str()?;
+            let series = format_series(shown.get("metric")?);
+            Some((
+                format_series(item.get("metric")?),
+                (series, value.to_string()),
+            ))
+        })
+        .collect())
 }
 
-fn post_form(
+/// Joins baseline and current samples by series, with the percentage change.
+fn compare_rows(
     cli: &Cli,
-    client: &Client,
-    url: Url,
-    params: Vec<(String, String)>,
-) -> Result<ApiResponse> {
-    let request = client.post(url).form(&params);
-    let request = apply_auth(request, cli)?;
-    let response = request.send().context("request failed")?;
-    parse_response(response)
+    before: &BTreeMap<String, (String, String)>,
+    now: &BTreeMap<String, (String, String)>,
+) -> Vec<Vec<String>> {
+    let series: BTreeSet<&String> = before.keys().chain(now.keys()).collect();
+    series
+        .into_iter()
+        .map(|key| {
+            let (old, new) = (before.get(key), now.get(key));
+            let name = old
+                .or(new)
+                .map(|(name, _)| name.clone())
+                .unwrap_or_default();
+            let (old, new) = (old.map(|(_, v)| v), new.map(|(_, v)| v));
+            let change = match (old, new) {
+                (None, _) => "new".to_string(),
+                (_, None) => "gone".to_string(),
+                (Some(old), Some(new)) => match (old.parse::<f64>(), new.parse::<f64>()) {
+                    (Ok(old), Ok(new)) if old != 0.0 => {
+                        format!("{:+.1}%", (new - old) / old.abs() * 100.0)
+                    }
+                    _ => "n/a".to_string(),
+                },
+            };
+            let cell =
+                |value: Option<&String>| value.map_or_else(String::new, |v| format_value(cli, v));
+            vec![name, cell(old), cell(new), change]
+        })
+        .collect()
 }
 
-fn get_query(
-    cli: &Cli,
-    client: &Client,
-    url: Url,
-    params: Vec<(String, String)>,
-) -> Result<ApiResponse> {
-    let request = client.get(url).query(&params);
-    let request = apply_auth(request, cli)?;
-    let response = request.send().context("request failed")?;
-    parse_response(response)
+/// One `[series, timestamp]` row per vector sample, optionally oldest first.
+fn latest_timestamps(data: &Value, oldest_first: bool) -> Result<Vec<Vec<String>>> {
+    if data.get("resultType").and_then(Value::as_str) != Some("vector") {
+        bail!("--latest-timestamp expects a vector result");
+    }
+    let mut samples: Vec<(String, f64)> = data
+        .get("result")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|item| {
+            let timestamp = item.get("value")?.get(0)?.as_f64()?;
+            Some((format_series(item.get("metric")?), timestamp))
+        })
+        .collect();
+    if oldest_first {
+        samples.sort_by(|a, b| a.1.total_cmp(&b.1));
+    }
+    Ok(samples
+        .into_iter()
+        .map(|(series, timestamp)| vec![series, format_timestamp(timestamp)])
+        .collect())
 }
 
-fn parse_response(response: reqwest::blocking::Response) -> Result<ApiResponse> {
-    let status = response.status();
-    let text = response.text().context("failed to read response body")?;
-    let parsed: ApiResponse = serde_json::from_str(&text).with_context(|| {
-        let preview = text.chars().take(200).collect::<String>();
-        format!("failed to parse response as JSON (status {status}): {preview}")
-    })?;
+/// Reshapes a matrix into timestamp rows with one column per distinct value of
+/// `label`. Each column must come from exactly one series.
+fn pivot_rows(cli: &Cli, data: &Value, label: &str) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+    if data.get("resultType").and_then(Value::as_str) != Some("matrix") {
+        bail!("--pivot-by expects a matrix result");
+    }
+    let series = data
+        .get("result")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+
+    let mut columns: Vec<String> = Vec::new();
+    // Rows keyed by millisecond so equal timestamps line up across series.
+    let mut cells: BTreeMap<i64, (f64, BTreeMap<usize, String>)> = BTreeMap::new();
+    for item in series {
+        let metric = item.get("metric").unwrap_or(&Value::Null);
+        let Some(column) = metric.get(label).and_then(Value::as_str) else {
+            bail!(
+                "series {} has no {label:?} label to pivot by",
+                format_series(metric)
+            );
+        };
+        if columns.iter().any(|existing| existing == column) {
+            bail!("--pivot-by {label} is ambiguous: several series have {label}={column:?}");
+        }
+        columns.push(column.to_string());
+        let index = columns.len() - 1;
+        for sample in item
+            .get("values")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            let (Some(timestamp), Some(value)) = (
+                sample.get(0).and_then(Value::as_f64),
+                sample.get(1).and_then(Value::as_str),
+            ) else {
+                continue;
+            };
+            cells
+                .entry((timestamp * 1000.0).round() as i64)
+                .or_insert_with(|| (timestamp, BTreeMap::new()))
+                .1
+                .insert(index, format_value(cli, value));
+        }
+    }
+
+    let mut previous = vec![String::new(); columns.len()];
+    let rows = cells
+        .into_values()
+        .map(|(timestamp, mut values)| {
+            let mut row = vec![format_timestamp(timestamp)];
+            row.extend(
+                (0..columns.len()).map(|i| match (values.remove(&i), cli.fill) {
+                    (Some(value), _) => {
+                        previous[i].clone_from(&value);
+                        value
+                    }
+                    (None, Fill::Empty) => String::new(),
+                    (None, Fill::Zero) => format_value(cli, "0"),
+                    (None, Fill::Previous) => previous[i].clone(),
+                    (None, Fill::Nan) => "NaN".to_string(),
+                }),
+            );
+            row
+        })
+        .collect();
+    let headers = std::iter::once("TIMESTAMP".to_string())
+        .chain(columns)
+        .collect();
+    Ok((headers, rows))
+}
 
-    if parsed.status != "success" {
-        let error_type = parsed.error_type.unwrap_or_else(|| "unknown".to_string());
-        let error = parsed.error.unwrap_or_else(|| "unknown error".to_string());
-        bail!("API error ({error_type}): {error}");
+/// One column per label name (`__name__` first, as NAME), then TIMESTAMP and VALUE,
+/// with a row per vector sample or matrix point.
+fn sample_rows(cli: &Cli, data: &Value) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+    let result = data.get("result").unwrap_or(&Value::Null);
+    match data.get("resultType").and_then(Value::as_str) {
+        Some("vector" | "matrix") => {}
+        Some("scalar" | "string") => {
+            let row = vec![
+                format_timestamp(result.get(0).and_then(Value::as_f64).unwrap_or_default()),
+                format_value(
+                    cli,
+                    result.get(1).and_then(Value::as_str).unwrap_or_default(),
+                ),
+            ];
+            return Ok((vec!["TIMESTAMP".into(), "VALUE".into()], vec![row]));
+        }
+        _ => bail!("expected a vector, matrix, scalar or string result"),
+    }
+    let series = result.as_array().map(Vec::as_slice).unwrap_or_default();
+    let mut labels: Vec<String> = series
+        .iter()
+        .filter_map(|item| item.get("metric").and_then(Value::as_object))
+        .flat_map(|metric| metric.keys().cloned())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    if let Some(i) = labels.iter().position(|name| name == "__name__") {
+        let name = labels.remove(i);
+        labels.insert(0, name);
     }
 
-    if let Some(warnings) = &parsed.warnings {
-        for warning in warnings {
-            eprintln!("warning: {warning}");
+    let mut rows = Vec::new();
+    for item in series {
+        let metric = item.get("metric").unwrap_or(&Value::Null);
+        let label_cells: Vec<String> = labels
+            .iter()
+            .map(|name| {
+                metric
+                    .get(name)
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .collect();
+        let samples = match item.get("values").and_then(Value::as_array) {
+            Some(values) => values.iter().collect(),
+            None => item.get("value").into_iter().collect::<Vec<_>>(),
+        };
+        for sample in samples {
+            let mut row = label_cells.clone();
+            row.push(format_timestamp(
+                sample.get(0).and_then(Value::as_f64).unwrap_or_default(),
+            ));
+            row.push(format_value(
+                cli,
+                sample.get(1).and_then(Value::as_str).unwrap_or_default(),
+            ));
+            rows.push(row);
         }
     }
+    // `__name__` would render as bold "name" in Markdown.
+    let headers = labels
+        .into_iter()
+        .map(|name| {
+            if name == "__name__" {
+                "NAME".to_string()
+            } else {
+                name
+            }
+        })
+        .chain(["TIMESTAMP".to_string(), "VALUE".to_string()])
+        .collect();
+    Ok((headers, rows))
+}
 
-    Ok(parsed)
+/// Pairs every job value with its `count by (job) (up)` sample (0 for jobs
+/// without `up` series), sorted by count descending and then by name.
+fn job_count_rows(jobs: Option<&Value>, counts: Option<&Value>) -> Vec<Vec<String>> {
+    let counts: HashMap<&str, u64> = counts
+        .and_then(|data| data.get("result"))
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|item| {
+            let job = item.pointer("/metric/job").and_then(Value::as_str)?;
+            let count = item.pointer("/value/1").and_then(Value::as_str)?;
+            Some((job, count.parse::<f64>().ok()? as u64))
+        })
+        .collect();
+    let mut rows: Vec<(&str, u64)> = jobs
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(|job| (job, counts.get(job).copied().unwrap_or(0)))
+        .collect();
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    rows.into_iter()
+        .map(|(job, count)| vec![job.to_string(), count.to_string()])
+        .collect()
 }
 
-fn output_data(cli: &Cli, response: ApiResponse) -> Result<()> {
-    let data = response.data.unwrap_or(Value::Null);
-    let payload = if cli.result {
-        data.get("result").cloned().unwrap_or(data)
-    } else {
-        data
+/// Joins `up` samples with active targets on (job, instance). Series or targets
+/// present on only one side are still listed, with `-` for the missing columns.
+fn health_rows(up: Option<&Value>, targets: Option<&Value>) -> Vec<Vec<String>> {
+    let label = |labels: &Value, name: &str| {
+        labels
+            .get(name)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
     };
-    print_json(&payload, cli.pretty)
+
+    // (job, instance) -> [up, health, last error]
+    let mut joined: BTreeMap<(String, String), [String; 3]> = BTreeMap::new();
+    let empty = || ["-".to_string(), "-".to_string(), String::new()];
+
+    let samples = up
+        .and_then(|data| data.get("result"))
+        .and_then(Value::as_array);
+    for sample in samples.into_iter().flatten() {
+        let metric = sample.get("metric").unwrap_or(&Value::Null);
+        let key = (label(metric, "job"), label(metric, "instance"));
+        let state = match sample
+            .get("value")
+            .and_then(|v| v.get(1))
+            .and_then(Value::as_str)
+        {
+            Some("1") => "up",
+            Some(_) => "down",
+            None => "-",
+        };
+        joined.entry(key).or_insert_with(empty)[0] = state.to_string();
+    }
+
+    let active = targets
+        .and_then(|data| data.get("activeTargets"))
+        .and_then(Value::as_array);
+    for target in active.into_iter().flatten() {
+        let labels = target.get("labels").unwrap_or(&Value::Null);
+        let key = (label(labels, "job"), label(labels, "instance"));
+        let entry = joined.entry(key).or_insert_with(empty);
+        entry[1] = label(target, "health");
+        entry[2] = label(target, "lastError");
+    }
+
+    joined
+        .into_iter()
+        .map(|((job, instance), [up, health, error])| vec![job, instance, up, health, error])
+        .collect()
 }
 
-fn output_list(cli: &Cli, response: ApiResponse) -> Result<()> {
-    let data = response.data.unwrap_or(Value::Null);
-    if cli.lines {
-        print_lines(&data)
-    } else {
-        print_json(&data, cli.pretty)
+/// The display width (in characters) of each column: the longest of its
+/// header and cells. Shared by the plain and Markdown table printers.
+fn column_widths(headers: &[&str], rows: &[Vec<String>]) -> Vec<usize> {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
     }
+    widths
 }
 
-fn print_json(value: &Value, pretty: bool) -> Result<()> {
-    let output = if pretty {
-        serde_json::to_string_pretty(value)?
-    } else {
-        serde_json::to_string(value)?
-    };
-    println!("{output}");
+/// Prints rows as left-aligned columns separated by two spaces.
+fn print_table(headers: &[&str], rows: &[Vec<String>]) -> Result<()> {
+    let widths = column_widths(headers, rows);
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let header: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    for row in std::iter::once(&header).chain(rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| format!("{cell:<width$}", width = widths[i]))
+            .collect();
+        writeln!(stdout, "{}", cells.join("  ").trim_end())?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Like `print_table`, but as a GitHub-flavored Markdown table. Pipes are
+/// escaped and line breaks flattened so every cell stays in its column.
+fn print_markdown_table(headers: &[&str], rows: &[Vec<String>]) -> Result<()> {
+    let escape = |cell: &str| cell.replace('|', "\\|").replace(['\r', '\n'], " ");
+    let headers: Vec<String> = headers.iter().map(|h| escape(h)).collect();
+    let rows: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| row.iter().map(|cell| escape(cell)).collect())
+        .collect();
+    let header_refs: Vec<&str> = headers.iter().map(String::as_str).collect();
+    // The separator row needs at least three dashes per column.
+    let widths: Vec<usize> = column_widths(&header_refs, &rows)
+        .into_iter()
+        .map(|width| width.max(3))
+        .collect();
+
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let separator: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    for row in std::iter::once(&headers)
+        .chain(std::iter::once(&separator))
+        .chain(&rows)
+    {
+        let cells: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| format!("{cell:<width$}", width = widths[i]))
+            .collect();
+        writeln!(stdout, "| {} |", cells.join(" | "))?;
+    }
+    stdout.flush()?;
     Ok(())
 }
 
-fn print_lines(value: &Value) -> Result<()> {
+fn print_lines(value: &Value, page_size: Option<u64>, newline: bool) -> Result<()> {
     let Some(items) = value.as_array() else {
         bail!("expected an array response for lines output");
     };
-    for item in items {
-        if let Some(s) = item.as_str() {
-            println!("{s}");
-        } else {
-            println!("{item}");
+    let page_size = page_size.map_or(items.len().max(1), |n| n as usize);
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let mut remaining = items.len();
+    for page in items.chunks(page_size) {
+        for item in page {
+            remaining -= 1;
+            let newline = newline || remaining > 0;
+            if let Some(s) = item.as_str() {
+                write_line(&mut stdout, s, newline)?;
+            } else {
+                write_line(&mut stdout, &item.to_string(), newline)?;
+            }
         }
+        stdout.flush()?;
     }
     Ok(())
 }
 
+/// Applies `task` to every item using up to `concurrency` worker threads,
+/// returning results in input order. Fails with the first error encountered.
+fn run_concurrent<T, R, F>(items: &[T], concurrency: usize, task: F) -> Result<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<R>>>> = Mutex::new(items.iter().map(|_| None).collect());
+    std::thread::scope(|scope| {
+        for _ in 0..concurrency.min(items.len()) {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(i) else { break };
+                    let result = task(item);
+                    results.lock().unwrap()[i] = Some(result);
+                }
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every item is processed"))
+        .collect()
+}
+
+fn range_params(query: &str, start: &str, end: &str, step: &str) -> Vec<(String, String)> {
+    vec![
+        ("query".to_string(), query.to_string()),
+        ("start".to_string(), start.to_string()),
+        ("end".to_string(), end.to_string()),
+        ("step".to_string(), step.to_string()),
+    ]
+}
+
+/// The server-side query timeout: a --timeout flag, else the --deadline, else
+/// a PROMQL_TIMEOUT default.
+fn query_timeout(cli: &Cli, matches: &ArgMatches, timeout: &Option<String>) -> Option<String> {
+    let deadline = cli.deadline.map(prom_duration);
+    // Only commands with a --timeout argument pass one in.
+    let from_env = timeout.is_some()
+        && matches
+            .subcommand()
+            .is_some_and(|(_, sub)| sub.value_source("timeout") == Some(ValueSource::EnvVariable));
+    if from_env {
+        deadline.or_else(|| timeout.clone())
+    } else {
+        timeout.clone().or(deadline)
+    }
+}
+
+fn instant_params(query: &str, time: Option<&str>, timeout: Option<&str>) -> Vec<(String, String)> {
+    let mut params = vec![("query".to_string(), query.to_string())];
+    if let Some(time) = time {
+        params.push(("time".to_string(), time.to_string()));
+    }
+    if let Some(timeout) = timeout {
+        params.push(("timeout".to_string(), timeout.to_string()));
+    }
+    params
+}
+
 fn build_match_params(
     matches: Vec<String>,
     start: Option<String>,
@@ -368,6 +5170,177 @@ fn build_match_params(
     params
 }
 
+fn parse_label_pair(raw: &str) -> Result<(String, String), String> {
+    let Some((name, value)) = raw.split_once('=') else {
+        return Err("expected LABEL=VALUE".to_string());
+    };
+    let valid = name
+        .chars()
+        .enumerate()
+        .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+    if name.is_empty() || !valid {
+        return Err(format!("invalid label name: {name:?}"));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+fn parse_query_param(raw: &str) -> Result<(String, String), String> {
+    match raw.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err("expected KEY=VALUE".to_string()),
+    }
+}
+
+fn parse_header(raw: &str) -> Result<(String, String), String> {
+    let Some((name, value)) = raw.split_once(':') else {
+        return Err("expected NAME: VALUE".to_string());
+    };
+    let (name, value) = (name.trim(), value.trim());
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name {name:?}"))?;
+    HeaderValue::from_str(value).map_err(|_| format!("invalid value for header {name}"))?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Parses --output-file-rotate: a size with a B, KB, MB or GB suffix
+/// (powers of 1024), or else a duration.
+fn parse_rotate(raw: &str) -> Result<Rotate> {
+    let upper = raw.to_ascii_uppercase();
+    let Some(number) = upper.strip_suffix('B') else {
+        let age = parse_duration(raw)?;
+        if age.is_zero() {
+            bail!("invalid age {raw:?}: must be greater than zero");
+        }
+        return Ok(Rotate::Age(age));
+    };
+    let (digits, scale) = match number.char_indices().last() {
+        Some((i, 'K')) => (&number[..i], 1 << 10),
+        Some((i, 'M')) => (&number[..i], 1 << 20),
+        Some((i, 'G')) => (&number[..i], 1 << 30),
+        _ => (number, 1),
+    };
+    match digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|size| size.checked_mul(scale))
+    {
+        Some(size) if size > 0 => Ok(Rotate::Size(size)),
+        _ => bail!("invalid size {raw:?}: expected e.g. 512KB or 100MB"),
+    }
+}
+
+fn parse_rename_label(raw: &str) -> Result<(String, String), String> {
+    match raw.split_once('=') {
+        Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+            Ok((old.to_string(), new.to_string()))
+        }
+        _ => Err("expected OLD=NEW".to_string()),
+    }
+}
+
+/// Combines inline --match selectors with those from --match-file and the
+/// selector built from structured matchers (if any).
+fn collect_matches(matches: &[String], matchers: &MatcherArgs) -> Result<Vec<String>> {
+    let mut matches = matches.to_vec();
+    if let Some(path) = &matchers.match_file {
+        matches.extend(read_list_file(path)?);
+    }
+    if let Some(selector) = build_selector(matchers) {
+        matches.push(selector);
+    }
+    Ok(matches)
+}
+
+/// Like [`collect_matches`], adding one selector per series returned by
+/// --from-query.
+fn resolve_matches(
+    cli: &Cli,
+    client: &Client,
+    base: &Url,
+    matches: &[String],
+    matchers: &MatcherArgs,
+) -> Result<Vec<String>> {
+    let mut matches = collect_matches(matches, matchers)?;
+    let Some(query) = &matchers.from_query else {
+        return Ok(matches);
+    };
+    let url = api_url(base, "api/v1/query");
+    let response = post_form(cli, client, url, instant_params(query, None, None))?;
+    let data = response.data.unwrap_or(Value::Null);
+    if data.get("resultType").and_then(Value::as_str) != Some("vector") {
+        bail!("--from-query must return a vector result");
+    }
+    let selectors: BTreeSet<String> = data
+        .get("result")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|item| item.get("metric").and_then(Value::as_object))
+        .filter(|labels| !labels.is_empty())
+        .map(|labels| {
+            let parts: Vec<String> = labels
+                .iter()
+                .map(|(name, value)| {
+                    let value = value.as_str().unwrap_or_default();
+                    format!("{name}=\"{}\"", escape_label_value(value))
+                })
+                .collect();
+            format!("{{{}}}", parts.join(","))
+        })
+        .collect();
+    if selectors.is_empty() {
+        bail!("--from-query {query:?} returned no series to match");
+    }
+    matches.extend(selectors);
+    Ok(matches)
+}
+
+/// Reads one entry per line, skipping blank lines and `#` comments.
+fn read_list_file(path: &str) -> Result<Vec<String>> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+fn build_selector(matchers: &MatcherArgs) -> Option<String> {
+    let groups = [
+        ("=", &matchers.eq),
+        ("!=", &matchers.ne),
+        ("=~", &matchers.re),
+        ("!~", &matchers.nre),
+    ];
+    let parts: Vec<String> = groups
+        .iter()
+        .flat_map(|(op, pairs)| {
+            pairs
+                .iter()
+                .map(move |(name, value)| format!("{name}{op}\"{}\"", escape_label_value(value)))
+        })
+        .collect();
+    if parts.is_empty() {
+        None
+    } else {
+        Some(format!("{{{}}}", parts.join(",")))
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 fn filter_values(response: ApiResponse, filter: &str) -> Result<ApiResponse> {
     let filter = filter.to_lowercase();
     let data = response.data.unwrap_or(Value::Null);
@@ -378,6 +5351,7 @@ fn filter_values(response: ApiResponse, filter: &str) -> Result<ApiResponse> {
             error_type: response.error_type,
             error: response.error,
             warnings: response.warnings,
+            meta: response.meta,
         });
     };
 
@@ -397,5 +5371,528 @@ fn filter_values(response: ApiResponse, filter: &str) -> Result<ApiResponse> {
         error_type: response.error_type,
         error: response.error,
         warnings: response.warnings,
+        meta: response.meta,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_cli(args: &[&str]) -> Cli {
+        let base = [
+            "promqlcli",
+            "--no-env",
+            "--base-url",
+            "http://prometheus:9090",
+        ];
+        Cli::try_parse_from(base.iter().chain(args)).unwrap()
+    }
+
+    fn decode(encoded: &[u8]) -> Vec<(String, String)> {
+        url::form_urlencoded::parse(encoded)
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn encode_params_round_trips_through_get_and_post() {
+        let query = r#"sum by (job) (rate(http_requests_total{code=~"5.."}[5m]))"#;
+        let params = vec![("query".to_string(), query.to_string())];
+        let cases = [
+            (
+                "form",
+                "sum+by+%28job%29+%28rate%28http_requests_total%7Bcode%3D%7E%225..%22%7D%5B5m%5D%29%29",
+            ),
+            (
+                "query",
+                "sum%20by%20%28job%29%20%28rate%28http_requests_total%7Bcode%3D~%225..%22%7D%5B5m%5D%29%29",
+            ),
+        ];
+        for (encoding, expected) in cases {
+            let cli = parse_cli(&["--encoding", encoding, "query", "up"]);
+            let encoded = encode_params(&cli, &params);
+            assert_eq!(
+                encoded,
+                format!("query={expected}"),
+                "--encoding {encoding}"
+            );
+
+            let mut url = Url::parse("http://prometheus:9090/api/v1/query").unwrap();
+            url.set_query(Some(&encoded));
+            assert_eq!(
+                url.query(),
+                Some(encoded.as_str()),
+                "--encoding {encoding} GET"
+            );
+            assert_eq!(decode(url.query().unwrap().as_bytes()), params);
+
+            let request = Client::new().post(url).body(encoded).build().unwrap();
+            let body = request.body().and_then(|body| body.as_bytes()).unwrap();
+            assert_eq!(decode(body), params, "--encoding {encoding} POST");
+        }
+    }
+
+    #[test]
+    fn check_range_accepts_ordered_bounds() {
+        assert_eq!(check_range("100", "200", false).unwrap(), ("100", "200"));
+        let (start, end) = ("2024-01-01T00:00:00Z", "2024-01-01T01:00:00Z");
+        assert_eq!(check_range(start, end, false).unwrap(), (start, end));
+    }
+
+    #[test]
+    fn check_range_rejects_reversed_bounds() {
+        assert!(check_range("200", "100", false).is_err());
+        assert!(check_range("2024-01-01T01:00:00Z", "2024-01-01T00:00:00Z", false).is_err());
+        // Mixed forms are compared as instants, not as strings.
+        assert!(check_range("2024-01-01T00:00:00Z", "1704067199", false).is_err());
+    }
+
+    #[test]
+    fn check_range_rejects_equal_bounds_even_with_auto_swap() {
+        for auto_swap in [false, true] {
+            assert!(check_range("100", "100", auto_swap).is_err());
+            assert!(check_range("2024-01-01T00:00:00Z", "1704067200", auto_swap).is_err());
+            assert!(
+                check_range(
+                    "2024-01-01T05:30:00+05:30",
+                    "2024-01-01T00:00:00Z",
+                    auto_swap
+                )
+                .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn check_range_auto_swap_reorders_reversed_bounds() {
+        assert_eq!(check_range("200", "100", true).unwrap(), ("100", "200"));
+        let (start, end) = ("2024-01-01T01:00:00Z", "2024-01-01T00:00:00Z");
+        assert_eq!(check_range(start, end, true).unwrap(), (end, start));
+        assert_eq!(
+            check_range("1704067300", "2024-01-01T00:00:00Z", true).unwrap(),
+            ("2024-01-01T00:00:00Z", "1704067300")
+        );
+    }
+
+    #[test]
+    fn command_times_to_secs_converts_ms_params_only() {
+        let mut cli = parse_cli(&[
+            "--time-unit",
+            "ms",
+            "range",
+            "up",
+            "--start",
+            "1700000000123",
+            "--end",
+            "2023-11-14T22:13:20.5+05:30",
+        ]);
+        command_times_to_secs(&mut cli.command);
+        let Commands::Range { start, end, .. } = &cli.command else {
+            unreachable!()
+        };
+        let params = range_params("up", start, end, "60s");
+        assert_eq!(
+            params[1],
+            ("start".to_string(), "1700000000.123".to_string())
+        );
+        assert_eq!(
+            params[2],
+            ("end".to_string(), "2023-11-14T22:13:20.5+05:30".to_string())
+        );
+
+        let mut cli = parse_cli(&[
+            "--time-unit",
+            "ms",
+            "query",
+            "up",
+            "--time",
+            "1700000000123.5",
+        ]);
+        command_times_to_secs(&mut cli.command);
+        let Commands::Query { time, .. } = &cli.command else {
+            unreachable!()
+        };
+        let secs = parse_time(time.as_deref().unwrap()).unwrap();
+        assert!((secs - 1_700_000_000.123_5).abs() < 1e-6, "{secs}");
+    }
+
+    #[test]
+    fn sample_timestamps_to_ms_round_trips_ms_inputs() {
+        let mut vector = serde_json::json!({
+            "resultType": "vector",
+            "result": [{"metric": {}, "value": [1700000000.123, "1"]}],
+        });
+        sample_timestamps_to_ms(&mut vector);
+        assert_eq!(vector["result"][0]["value"][0], 1700000000123_i64);
+
+        // Fractional milliseconds are rounded to the nearest one.
+        let mut matrix = serde_json::json!({
+            "resultType": "matrix",
+            "result": [{"metric": {}, "values": [[1700000000.1234, "1"], [1700000060.9996, "2"]]}],
+        });
+        sample_timestamps_to_ms(&mut matrix);
+        assert_eq!(matrix["result"][0]["values"][0][0], 1700000000123_i64);
+        assert_eq!(matrix["result"][0]["values"][1][0], 1700000061000_i64);
+        assert_eq!(matrix["result"][0]["values"][1][1], "2");
+
+        let mut scalar = serde_json::json!({"resultType": "scalar", "result": [1700000000.5, "3"]});
+        sample_timestamps_to_ms(&mut scalar);
+        assert_eq!(scalar["result"][0], 1700000000500_i64);
+    }
+
+    #[test]
+    fn snap_range_aligns_offset_inputs_to_utc_boundaries() {
+        // 04:47:45Z and 06:47:15Z the next day.
+        let (start, end) = ("2024-01-01T10:17:45+05:30", "2024-01-01T22:47:15-08:00");
+        let cases = [
+            (60.0, "1704084420", "1704178080"),
+            (3600.0, "1704081600", "1704178800"),
+            (86400.0, "1704067200", "1704240000"),
+        ];
+        for (unit, aligned_start, aligned_end) in cases {
+            let (start, end) = snap_range(start, end, unit).unwrap();
+            assert_eq!(
+                (start.as_str(), end.as_str()),
+                (aligned_start, aligned_end),
+                "{unit}s"
+            );
+        }
+    }
+
+    #[test]
+    fn snap_range_keeps_aligned_bounds() {
+        // UTC midnight on 2024-01-02 and 2024-01-03.
+        let (start, end) = ("2024-01-02T05:30:00+05:30", "2024-01-02T16:00:00-08:00");
+        for unit in [60.0, 3600.0, 86400.0] {
+            let (start, end) = snap_range(start, end, unit).unwrap();
+            assert_eq!(
+                (start.as_str(), end.as_str()),
+                ("1704153600", "1704240000"),
+                "{unit}s"
+            );
+        }
+        let (start, end) = snap_range("1704153600", "1704157200", 3600.0).unwrap();
+        assert_eq!((start.as_str(), end.as_str()), ("1704153600", "1704157200"));
+    }
+
+    #[test]
+    fn api_url_appends_to_base_path() {
+        let base = normalize_base(&parse_cli(&["ready"]), "https://host/prom").unwrap();
+        assert_eq!(
+            api_url(&base, "api/v1/query").as_str(),
+            "https://host/prom/api/v1/query"
+        );
+
+        let raw = parse_cli(&["--no-normalize-base", "ready"]);
+        let base = normalize_base(&raw, "https://host/prom/").unwrap();
+        assert_eq!(
+            api_url(&base, "api/v1/query").as_str(),
+            "https://host/prom/api/v1/query"
+        );
+        let base = normalize_base(&raw, "https://host/prom?x=1").unwrap();
+        assert_eq!(base.path(), "/prom");
+        assert_eq!(
+            api_url(&base, "api/v1/query").as_str(),
+            "https://host/prom/api/v1/query"
+        );
+        let base = normalize_base(&raw, "https://host").unwrap();
+        assert_eq!(api_url(&base, "-/ready").as_str(), "https://host/-/ready");
+    }
+
+    #[test]
+    fn parse_rotate_rejects_zero_and_overflowing_limits() {
+        assert!(matches!(parse_rotate("512KB"), Ok(Rotate::Size(524_288))));
+        assert!(matches!(parse_rotate("30m"), Ok(Rotate::Age(age)) if age.as_secs() == 1800));
+        for raw in ["0B", "0MB", "0s", "99999999999GB", "18446744073709551615KB"] {
+            assert!(parse_rotate(raw).is_err(), "{raw}");
+        }
+    }
+
+    #[test]
+    fn auth_hint_names_credential_source() {
+        let hint = |args: &[&str]| auth_hint(&parse_cli(args));
+        assert!(hint(&["--bearer", "t", "ready"]).ends_with("check --bearer"));
+        assert!(hint(&["--auth-command", "echo t", "ready"]).contains("--auth-command"));
+        assert!(hint(&["--auth-file", "/dev/null", "ready"]).contains("--auth-file"));
+        assert!(hint(&["--user", "u", "--password", "p", "ready"]).contains("--user/--password"));
+
+        let dir = std::env::temp_dir().join(format!("promqlcli-secrets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("username"), "u\n").unwrap();
+        std::fs::write(dir.join("password"), "p\n").unwrap();
+        let mut cli = parse_cli(&["ready"]);
+        load_secrets_dir(&mut cli, dir.to_str().unwrap()).unwrap();
+        assert!(auth_hint(&cli).contains("username and password files in --secrets-dir"));
+
+        std::fs::write(dir.join("token"), "t\n").unwrap();
+        let mut cli = parse_cli(&["ready"]);
+        load_secrets_dir(&mut cli, dir.to_str().unwrap()).unwrap();
+        assert!(auth_hint(&cli).contains("token file in --secrets-dir"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn redact_value_covers_named_label_and_regex() {
+        let cli = parse_cli(&["--redact", "instance", "--redact-regex", "^prod-", "ready"]);
+        let redacted = |name: &str, value: &str| {
+            let mut value = value.to_string();
+            redact_value(&cli, name, &mut value);
+            value
+        };
+        assert_eq!(redacted("instance", "10.0.0.1:9100"), REDACTED);
+        assert_eq!(redacted("job", "prod-api"), REDACTED);
+        assert_eq!(redacted("job", "node"), "node");
+        assert_eq!(redacted("instance", ""), "");
+    }
+
+    #[test]
+    fn print_changes_tracks_raw_series_under_redact() {
+        let cli = parse_cli(&["--redact", "instance", "ready"]);
+        let data = serde_json::json!({
+            "resultType": "vector",
+            "result": [
+                {"metric": {"job": "node", "instance": "a"}, "value": [1700000000, "1"]},
+                {"metric": {"job": "node", "instance": "b"}, "value": [1700000000, "0"]},
+            ],
+        });
+        let mut last_seen = HashMap::new();
+        let mut out = Vec::new();
+        print_changes(&cli, &data, &mut last_seen, &mut out).unwrap();
+        let printed = String::from_utf8(out).unwrap();
+        assert_eq!(printed.lines().count(), 2);
+        assert!(!printed.contains("\"a\"") && printed.contains(REDACTED));
+
+        let mut out = Vec::new();
+        print_changes(&cli, &data, &mut last_seen, &mut out).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn compare_joins_on_raw_series_under_redact() {
+        let cli = parse_cli(&["--redact", "instance", "ready"]);
+        let vector = |a: &str, b: &str| {
+            serde_json::json!({
+                "resultType": "vector",
+                "result": [
+                    {"metric": {"instance": "a"}, "value": [1700000000, a]},
+                    {"metric": {"instance": "b"}, "value": [1700000000, b]},
+                ],
+            })
+        };
+        let before = vector_samples(&cli, &vector("10", "100")).unwrap();
+        let now = vector_samples(&cli, &vector("20", "50")).unwrap();
+        let rows = compare_rows(&cli, &before, &now);
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(|row| row[0] == "{instance=\"REDACTED\"}"));
+        assert_eq!(rows[0][3], "+100.0%");
+        assert_eq!(rows[1][3], "-50.0%");
+    }
+
+    #[test]
+    fn expand_at_rewrites_relative_and_rfc3339_modifiers() {
+        let now = 1_700_000_000.0;
+        let expand = |query: &str| expand_at(query, now).unwrap();
+        assert_eq!(expand("up @ now"), "up @ 1700000000");
+        assert_eq!(expand("up @ now-1h"), "up @ 1699996400");
+        assert_eq!(
+            expand("rate(up[5m] @ now+30m)"),
+            "rate(up[5m] @ 1700001800)"
+        );
+        assert_eq!(expand("up @ 2024-01-01T00:00:00Z"), "up @ 1704067200");
+        assert_eq!(
+            expand("up @ 2024-01-01T05:30:00+05:30 offset 5m"),
+            "up @ 1704067200 offset 5m"
+        );
+    }
+
+    #[test]
+    fn expand_at_keeps_literals_and_other_modifiers() {
+        let expand = |query: &str| expand_at(query, 1_700_000_000.0).unwrap();
+        for query in [
+            "up @ 1609459200",
+            "up @ start()",
+            "rate(up[5m] @ end())",
+            r#"up{job="@ now"}"#,
+            r#"up{job="a\"@ now"}"#,
+            "label_replace(up, 'x', '@ now-1h', '', '')",
+            "up{job=`@ now`}",
+        ] {
+            assert_eq!(expand(query), query);
+        }
+        assert_eq!(
+            expand(r#"up{job="@ now"} @ now"#),
+            r#"up{job="@ now"} @ 1700000000"#
+        );
+    }
+
+    #[test]
+    fn expand_at_rejects_malformed_relative_modifiers() {
+        for query in ["up @ now-1x", "up @ now-", "up @ now+h"] {
+            assert!(expand_at(query, 1_700_000_000.0).is_err(), "{query}");
+        }
+    }
+
+    #[test]
+    fn prom_duration_avoids_fractional_seconds() {
+        assert_eq!(prom_duration(Duration::from_secs(30)), "30s");
+        assert_eq!(prom_duration(Duration::from_millis(1500)), "1500ms");
+        assert_eq!(prom_duration(Duration::from_millis(250)), "250ms");
+        let cli = parse_cli(&["--deadline", "1500ms", "ready"]);
+        let matches = Cli::command().get_matches_from(["promqlcli", "--base-url", "x", "ready"]);
+        assert_eq!(
+            query_timeout(&cli, &matches, &None).as_deref(),
+            Some("1500ms")
+        );
+    }
+
+    fn matchers(
+        eq: &[(&str, &str)],
+        ne: &[(&str, &str)],
+        re: &[(&str, &str)],
+        nre: &[(&str, &str)],
+    ) -> MatcherArgs {
+        let pairs = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+        MatcherArgs {
+            eq: pairs(eq),
+            ne: pairs(ne),
+            re: pairs(re),
+            nre: pairs(nre),
+            match_file: None,
+            from_query: None,
+        }
+    }
+
+    #[test]
+    fn build_selector_renders_each_operator() {
+        assert!(build_selector(&matchers(&[], &[], &[], &[])).is_none());
+        let selector = build_selector(&matchers(
+            &[("job", "api")],
+            &[("env", "prod")],
+            &[("instance", "web.*")],
+            &[("instance", "db.*")],
+        ));
+        assert_eq!(
+            selector.as_deref(),
+            Some(r#"{job="api",env!="prod",instance=~"web.*",instance!~"db.*"}"#)
+        );
+        let selector = build_selector(&matchers(&[("job", "a"), ("env", "b")], &[], &[], &[]));
+        assert_eq!(selector.as_deref(), Some(r#"{job="a",env="b"}"#));
+    }
+
+    #[test]
+    fn build_selector_escapes_label_values() {
+        assert_eq!(escape_label_value(r#"say "hi""#), r#"say \"hi\""#);
+        assert_eq!(escape_label_value(r"C:\temp"), r"C:\\temp");
+        assert_eq!(escape_label_value("a\nb"), r"a\nb");
+        assert_eq!(escape_label_value(r#"\""#), r#"\\\""#);
+        let selector = build_selector(&matchers(
+            &[("path", r#"C:\"x""#)],
+            &[],
+            &[("msg", "line1\nline2")],
+            &[("re", r"\d+")],
+        ));
+        assert_eq!(
+            selector.as_deref(),
+            Some(r#"{path="C:\\\"x\"",msg=~"line1\nline2",re!~"\\d+"}"#)
+        );
+    }
+
+    #[test]
+    fn chunk_range_tiles_the_step_grid() {
+        let chunks = chunk_range("1700000000", "1700000600", "60s", 4).unwrap();
+        assert_eq!(
+            chunks,
+            [
+                ("1700000000".to_string(), "1700000180".to_string()),
+                ("1700000240".to_string(), "1700000420".to_string()),
+                ("1700000480".to_string(), "1700000600".to_string()),
+            ]
+        );
+        // Each chunk starts one step after the previous one ends: no overlap, no gap.
+        for pair in chunks.windows(2) {
+            let prev_end: f64 = pair[0].1.parse().unwrap();
+            let next_start: f64 = pair[1].0.parse().unwrap();
+            assert_eq!(next_start, prev_end + 60.0);
+        }
+
+        let chunks = chunk_range("2023-11-14T22:13:20Z", "1700000360", "1m", 4).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(
+            chunks[1],
+            ("1700000240".to_string(), "1700000360".to_string())
+        );
+
+        let chunks = chunk_range("1700000000", "1700000000", "60s", 11_000).unwrap();
+        assert_eq!(
+            chunks,
+            [("1700000000".to_string(), "1700000000".to_string())]
+        );
+        assert!(chunk_range("1700000000", "1700000600", "0s", 4).is_err());
+    }
+
+    fn matrix_response(result: Value) -> ApiResponse {
+        ApiResponse {
+            status: "success".to_string(),
+            data: Some(serde_json::json!({ "resultType": "matrix", "result": result })),
+            error_type: None,
+            error: None,
+            warnings: None,
+            meta: ResponseMeta::default(),
+        }
+    }
+
+    #[test]
+    fn stitch_matrices_merges_series_across_chunks() {
+        let first = matrix_response(serde_json::json!([
+            {"metric": {"job": "a"}, "values": [[0, "1"], [60, "2"]]},
+        ]));
+        let second = matrix_response(serde_json::json!([
+            {"metric": {"job": "b"}, "values": [[120, "5"]]},
+            {"metric": {"job": "a"}, "values": [[120, "3"]]},
+        ]));
+        let stitched = stitch_matrices(vec![first, second]).unwrap();
+        assert_eq!(
+            stitched.data.unwrap(),
+            serde_json::json!({
+                "resultType": "matrix",
+                "result": [
+                    {"metric": {"job": "a"}, "values": [[0, "1"], [60, "2"], [120, "3"]]},
+                    {"metric": {"job": "b"}, "values": [[120, "5"]]},
+                ],
+            })
+        );
+
+        let mut vector = matrix_response(serde_json::json!([]));
+        vector.data = Some(serde_json::json!({ "resultType": "vector", "result": [] }));
+        assert!(stitch_matrices(vec![vector]).is_err());
+    }
+
+    #[test]
+    fn lttb_keeps_endpoints_and_spikes() {
+        let samples: Vec<Value> = (0..10)
+            .map(|i| serde_json::json!([i * 60, if i == 5 { "100" } else { "0" }]))
+            .collect();
+        let kept = lttb(&samples, 4);
+        assert_eq!(kept.len(), 4);
+        assert_eq!(kept[0], samples[0]);
+        assert_eq!(kept[3], samples[9]);
+        assert!(kept.contains(&samples[5]));
+        let timestamps: Vec<f64> = kept.iter().map(|s| s[0].as_f64().unwrap()).collect();
+        assert!(timestamps.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn lttb_passes_short_series_through() {
+        let samples: Vec<Value> = (0..5).map(|i| serde_json::json!([i, "1"])).collect();
+        assert_eq!(lttb(&samples, 5), samples);
+        assert_eq!(lttb(&samples, 10), samples);
+        assert_eq!(lttb(&samples, 2), samples);
+        assert_eq!(lttb(&samples, 3).len(), 3);
+    }
+}
@@ -0,0 +1,97 @@
+//! Renders range query (matrix) results as a PNG line chart, one line per
+//! series with a legend built from its label set.
+
+use anyhow::{Context, Result, bail};
+use chrono::DateTime;
+use plotters::prelude::*;
+use serde_json::Value;
+
+use crate::format_series;
+
+/// Draws every series of `data` to `path` and returns the number of series plotted.
+pub fn render_matrix(data: &Value, path: &str, width: u32, height: u32) -> Result<usize> {
+    if data.get("resultType").and_then(Value::as_str) != Some("matrix") {
+        bail!("plot expects a matrix result");
+    }
+    let series: Vec<(String, Vec<(f64, f64)>)> = data
+        .get("result")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|item| {
+            let label = format_series(item.get("metric").unwrap_or(&Value::Null));
+            let points = item
+                .get("values")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|sample| {
+                    let timestamp = sample.get(0)?.as_f64()?;
+                    let value = sample.get(1)?.as_str()?.parse::<f64>().ok()?;
+                    value.is_finite().then_some((timestamp, value))
+                })
+                .collect();
+            (label, points)
+        })
+        .collect();
+
+    let points = series.iter().flat_map(|(_, points)| points);
+    let (mut x_min, mut x_max, mut y_min, mut y_max) = (f64::MAX, f64::MIN, f64::MAX, f64::MIN);
+    for &(x, y) in points {
+        x_min = x_min.min(x);
+        x_max = x_max.max(x);
+        y_min = y_min.min(y);
+        y_max = y_max.max(y);
+    }
+    if x_min > x_max {
+        bail!("no samples to plot");
+    }
+    // Give flat lines and single samples a visible range.
+    if x_min == x_max {
+        x_max += 1.0;
+    }
+    if y_min == y_max {
+        y_min -= 1.0;
+        y_max += 1.0;
+    }
+    let time_format = if x_max - x_min < 86400.0 {
+        "%H:%M"
+    } else {
+        "%m-%d %H:%M"
+    };
+    let format_x = |x: &f64| {
+        DateTime::from_timestamp(*x as i64, 0)
+            .map(|time| time.format(time_format).to_string())
+            .unwrap_or_default()
+    };
+
+    let root = BitMapBackend::new(path, (width, height)).into_drawing_area();
+    root.fill(&WHITE)?;
+    let mut chart = ChartBuilder::on(&root)
+        .margin(10)
+        .x_label_area_size(30)
+        .y_label_area_size(60)
+        .build_cartesian_2d(x_min..x_max, y_min..y_max)?;
+    chart.configure_mesh().x_label_formatter(&format_x).draw()?;
+
+    for (index, (label, points)) in series.iter().enumerate() {
+        let color = Palette99::pick(index).to_rgba();
+        chart
+            .draw_series(LineSeries::new(
+                points.iter().copied(),
+                color.stroke_width(2),
+            ))?
+            .label(label)
+            .legend(move |(x, y)| {
+                PathElement::new(vec![(x, y), (x + 20, y)], color.stroke_width(2))
+            });
+    }
+    chart
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()?;
+    root.present()
+        .with_context(|| format!("failed to write {path}"))?;
+    Ok(series.len())
+}
@@ -0,0 +1,107 @@
+//! A file sink for long-running captures that moves on to a new numbered file
+//! once the current one grows past a size or age limit.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+/// When `--output-file-rotate` starts a new file.
+#[derive(Clone, Copy)]
+pub enum Rotate {
+    /// Once the current file holds at least this many bytes
+    Size(u64),
+    /// Once the current file has been open this long
+    Age(Duration),
+}
+
+/// Appends to `path`, then `path.1`, `path.2`, ... (resuming at the highest
+/// existing number). Rotation only happens at the start of a line, so no line
+/// is split across two files.
+pub struct RotatingFile {
+    path: String,
+    rotate: Option<Rotate>,
+    file: File,
+    index: u32,
+    written: u64,
+    opened: Instant,
+    line_start: bool,
+}
+
+impl RotatingFile {
+    pub fn create(path: &str, rotate: Option<Rotate>) -> Result<Self> {
+        let mut index = 0;
+        while Path::new(&numbered(path, index + 1)).exists() {
+            index += 1;
+        }
+        let current = numbered(path, index);
+        let (file, written) =
+            open_append(&current).with_context(|| format!("failed to open {current}"))?;
+        Ok(RotatingFile {
+            path: path.to_string(),
+            rotate,
+            file,
+            index,
+            written,
+            opened: Instant::now(),
+            line_start: true,
+        })
+    }
+
+    fn due(&self) -> bool {
+        match self.rotate {
+            Some(Rotate::Size(max)) => self.written >= max,
+            Some(Rotate::Age(max)) => self.opened.elapsed() >= max,
+            None => false,
+        }
+    }
+
+    fn next_file(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.index += 1;
+        let (file, written) = open_append(&numbered(&self.path, self.index))?;
+        self.file = file;
+        self.written = written;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.line_start && self.due() {
+            self.next_file()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        if written > 0 {
+            self.line_start = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// The name of the `index`th file: `path` itself, then `path.1`, `path.2`, ...
+fn numbered(path: &str, index: u32) -> String {
+    if index == 0 {
+        path.to_string()
+    } else {
+        format!("{path}.{index}")
+    }
+}
+
+/// Opens `path` for appending, returning it with its current length.
+fn open_append(path: &str) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let len = file.metadata()?.len();
+    Ok((file, len))
+}
@@ -0,0 +1,155 @@
+//! Parses a query API response incrementally, handing each `data.result`
+//! element to a callback as soon as it has been read so huge matrices never
+//! have to be held in memory at once.
+
+use std::fmt;
+use std::io::{BufReader, Read};
+
+use anyhow::Result;
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde_json::Value;
+
+/// The parts of the response envelope other than the streamed results.
+#[derive(Default)]
+pub struct Envelope {
+    pub status: String,
+    pub error_type: Option<String>,
+    pub error: Option<String>,
+    pub warnings: Vec<String>,
+    /// `data.resultType`, if present
+    pub result_type: Option<String>,
+    pub items: usize,
+}
+
+/// Reads a Prometheus API response from `reader`, calling `on_item` for each
+/// element of `data.result` in order.
+pub fn for_each_result<R, F>(reader: R, mut on_item: F) -> Result<Envelope>
+where
+    R: Read,
+    F: FnMut(Value) -> Result<()>,
+{
+    let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(reader));
+    let envelope = EnvelopeSeed {
+        on_item: &mut on_item,
+    }
+    .deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(envelope)
+}
+
+struct EnvelopeSeed<'a, F> {
+    on_item: &'a mut F,
+}
+
+impl<'de, F: FnMut(Value) -> Result<()>> DeserializeSeed<'de> for EnvelopeSeed<'_, F> {
+    type Value = Envelope;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<Envelope, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, F: FnMut(Value) -> Result<()>> Visitor<'de> for EnvelopeSeed<'_, F> {
+    type Value = Envelope;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an API response object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Envelope, A::Error> {
+        let mut envelope = Envelope::default();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "status" => envelope.status = map.next_value()?,
+                "errorType" => envelope.error_type = map.next_value()?,
+                "error" => envelope.error = map.next_value()?,
+                "warnings" => {
+                    envelope.warnings = map.next_value::<Option<_>>()?.unwrap_or_default()
+                }
+                "data" => map.next_value_seed(DataSeed {
+                    on_item: &mut *self.on_item,
+                    envelope: &mut envelope,
+                })?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(envelope)
+    }
+}
+
+struct DataSeed<'a, F> {
+    on_item: &'a mut F,
+    envelope: &'a mut Envelope,
+}
+
+impl<'de, F: FnMut(Value) -> Result<()>> DeserializeSeed<'de> for DataSeed<'_, F> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, F: FnMut(Value) -> Result<()>> Visitor<'de> for DataSeed<'_, F> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a data object")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "resultType" => self.envelope.result_type = map.next_value()?,
+                "result" => {
+                    self.envelope.items += map.next_value_seed(ResultSeed {
+                        on_item: &mut *self.on_item,
+                    })?
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct ResultSeed<'a, F> {
+    on_item: &'a mut F,
+}
+
+impl<'de, F: FnMut(Value) -> Result<()>> DeserializeSeed<'de> for ResultSeed<'_, F> {
+    type Value = usize;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, F: FnMut(Value) -> Result<()>> Visitor<'de> for ResultSeed<'_, F> {
+    type Value = usize;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a result array")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<usize, E> {
+        Ok(0)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<usize, A::Error> {
+        let mut count = 0;
+        while let Some(item) = seq.next_element::<Value>()? {
+            (self.on_item)(item).map_err(|err| de::Error::custom(format!("{err:#}")))?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}